@@ -0,0 +1,45 @@
+use indexer::indexer::{dot_product, dot_product_scalar};
+use std::hint::black_box;
+use std::time::Instant;
+// Compares the SIMD dot product with the scalar loop on an index sized like a mid-sized codebase embedded with
+// text-embedding-3-large. Run with `cargo bench --bench similarity`.
+
+const DIMENSIONS: usize = 3072;
+const ITEMS: usize = 4_000;
+const QUERIES: usize = 20;
+
+// Small linear congruential generator, keeps the benchmark free of extra dependencies and reproducible
+fn pseudo_random_vectors(count: usize, seed: u64) -> Vec<Vec<f32>> {
+	let mut state = seed;
+	(0..count)
+		.map(|_| {
+			(0..DIMENSIONS)
+				.map(|_| {
+					state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+					((state >> 40) as f32 / (1u64 << 24) as f32) - 0.5
+				})
+				.collect()
+		})
+		.collect()
+}
+
+fn time(label: &str, index: &[Vec<f32>], queries: &[Vec<f32>], dot: fn(&[f32], &[f32]) -> f32) -> f64 {
+	let start = Instant::now();
+	let mut checksum = 0.0;
+	for query in queries {
+		for item in index {
+			checksum += dot(black_box(query), black_box(item));
+		}
+	}
+	let per_query = start.elapsed().as_secs_f64() * 1000.0 / queries.len() as f64;
+	println!("{:>6}: {:.3} ms per query over {} items (checksum {:.3})", label, per_query, index.len(), checksum);
+	per_query
+}
+
+fn main() {
+	let index = pseudo_random_vectors(ITEMS, 1);
+	let queries = pseudo_random_vectors(QUERIES, 2);
+	let scalar = time("scalar", &index, &queries, dot_product_scalar);
+	let simd = time("simd", &index, &queries, dot_product);
+	println!("speedup: {:.2}x", scalar / simd);
+}
@@ -0,0 +1,164 @@
+use crate::indexer;
+// Approximate nearest neighbour search over the stored embeddings, an inverted file (IVF) index: the vectors are
+// clustered with k-means and a query only scans the clusters whose centroids are closest to it
+
+// Below this many vectors a single list is kept, scanning everything is cheap and exact
+pub const MIN_CLUSTERED_ITEMS: usize = 256;
+const KMEANS_ITERATIONS: usize = 10;
+
+// One IVF index over a list of embeddings, candidates are returned as positions in that list
+#[derive(Debug, Clone, Default)]
+pub struct IvfIndex {
+	dim: usize,
+	centroids: Vec<Vec<f32>>,
+	lists: Vec<Vec<usize>>,
+	// Number of closest clusters scanned per query, more is slower but closer to exact search
+	pub nprobe: usize,
+}
+
+// Separate indexes for files, objects and morphisms, mirroring how code_base stores them
+#[derive(Debug, Clone, Default)]
+pub struct AnnIndex {
+	pub files: IvfIndex,
+	pub objects: IvfIndex,
+	pub morphisms: IvfIndex,
+}
+
+impl AnnIndex {
+	pub fn build(code_base: &indexer::code_base) -> Self {
+		AnnIndex {
+			files: IvfIndex::build(&code_base.files.iter().map(|file| file.embedding.as_slice()).collect::<Vec<_>>()),
+			objects: IvfIndex::build(&code_base.objects.iter().map(|obj| obj.embedding.as_slice()).collect::<Vec<_>>()),
+			morphisms: IvfIndex::build(&code_base.morphisms.iter().map(|morph| morph.embedding.as_slice()).collect::<Vec<_>>()),
+		}
+	}
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+	indexer::dot_product(a, b)
+}
+
+fn nearest(centroids: &[Vec<f32>], vector: &[f32]) -> usize {
+	let mut best = 0;
+	let mut best_score = f32::MIN;
+	for (i, centroid) in centroids.iter().enumerate() {
+		let score = dot(centroid, vector);
+		if score > best_score {
+			best = i;
+			best_score = score;
+		}
+	}
+	best
+}
+
+impl IvfIndex {
+	// Clusters the vectors on the unit sphere so closeness matches cosine similarity. Empty or zero vectors, and
+	// vectors whose dimension differs from the first one, cannot be compared with a query and are left out.
+	pub fn build(vectors: &[&[f32]]) -> Self {
+		let dim = vectors.iter().find(|v| !v.is_empty()).map_or(0, |v| v.len());
+		let points: Vec<(usize, Vec<f32>)> = vectors
+			.iter()
+			.enumerate()
+			.filter(|(_, v)| v.len() == dim && dim > 0 && v.iter().any(|x| *x != 0.0))
+			.map(|(i, v)| {
+				let mut v = v.to_vec();
+				indexer::normalize(&mut v);
+				(i, v)
+			})
+			.collect();
+		if points.len() < MIN_CLUSTERED_ITEMS {
+			return IvfIndex {
+				dim,
+				centroids: Vec::new(),
+				lists: vec![points.iter().map(|(i, _)| *i).collect()],
+				nprobe: 1,
+			};
+		}
+
+		// k = sqrt(n) clusters, seeded with evenly spaced points so builds are deterministic
+		let k = (points.len() as f32).sqrt() as usize;
+		let mut centroids: Vec<Vec<f32>> = (0..k).map(|c| points[c * points.len() / k].1.clone()).collect();
+		let mut assignment = vec![0; points.len()];
+		for _ in 0..KMEANS_ITERATIONS {
+			for (p, (_, point)) in points.iter().enumerate() {
+				assignment[p] = nearest(&centroids, point);
+			}
+			let mut sums = vec![vec![0.0; dim]; k];
+			for (p, (_, point)) in points.iter().enumerate() {
+				for (sum, x) in sums[assignment[p]].iter_mut().zip(point.iter()) {
+					*sum += x;
+				}
+			}
+			// A cluster that lost all its points keeps its previous centroid
+			for (centroid, mut sum) in centroids.iter_mut().zip(sums) {
+				if sum.iter().any(|x| *x != 0.0) {
+					indexer::normalize(&mut sum);
+					*centroid = sum;
+				}
+			}
+		}
+		let mut lists = vec![Vec::new(); k];
+		for (p, (i, point)) in points.iter().enumerate() {
+			assignment[p] = nearest(&centroids, point);
+			lists[assignment[p]].push(*i);
+		}
+		IvfIndex {
+			dim,
+			centroids,
+			lists,
+			nprobe: (k / 4).max(1),
+		}
+	}
+
+	// Positions worth scoring for this query, in ascending order. A query of the wrong dimension matches nothing,
+	// just like in exact search.
+	pub fn candidates(&self, query: &[f32]) -> Vec<usize> {
+		if query.len() != self.dim {
+			return Vec::new();
+		}
+		let mut candidates: Vec<usize> = if self.centroids.is_empty() {
+			self.lists.concat()
+		} else {
+			let mut query = query.to_vec();
+			indexer::normalize(&mut query);
+			let mut order: Vec<(usize, f32)> = self.centroids.iter().map(|c| dot(c, &query)).enumerate().collect();
+			order.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+			order.iter().take(self.nprobe).flat_map(|(c, _)| self.lists[*c].iter().copied()).collect()
+		};
+		candidates.sort_unstable();
+		candidates
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn candidates_contain_the_exact_nearest_neighbour() {
+		// Points spread over a circle in the plane, with a few noisy dimensions
+		let vectors: Vec<Vec<f32>> = (0..1000)
+			.map(|i| {
+				let angle = i as f32 * 0.0063;
+				vec![angle.cos(), angle.sin(), (i % 7) as f32 * 0.01, (i % 3) as f32 * 0.01]
+			})
+			.collect();
+		let slices: Vec<&[f32]> = vectors.iter().map(|v| v.as_slice()).collect();
+		let index = IvfIndex::build(&slices);
+		assert!(index.centroids.len() > 1);
+		for probe in [0usize, 250, 640, 999] {
+			let query = &vectors[probe];
+			let candidates = index.candidates(query);
+			assert!(candidates.len() < vectors.len());
+			assert!(candidates.contains(&probe));
+		}
+		assert!(index.candidates(&[1.0, 0.0]).is_empty());
+	}
+
+	#[test]
+	fn small_inputs_keep_every_comparable_vector() {
+		let vectors: Vec<&[f32]> = vec![&[1.0, 0.0], &[], &[0.0, 0.0], &[0.5, 0.5], &[1.0]];
+		let index = IvfIndex::build(&vectors);
+		assert_eq!(index.candidates(&[0.0, 1.0]), vec![0, 3]);
+	}
+}
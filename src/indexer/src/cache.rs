@@ -0,0 +1,195 @@
+use crate::llm::{LlmError, LlmProvider};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+// On-disk cache of raw provider output, so re-running an index over unchanged files costs nothing
+
+// Default location of the cache, relative to the working directory
+pub const DEFAULT_CACHE_DIR: &str = ".eve_cache";
+
+// Wraps another provider and stores every canonicalization response, completion and embedding under a hash of the
+// request.
+// The model names are part of the key, so switching models never serves stale entries. Canonicalizations are also
+// keyed on the prompt, editing it re-extracts every file.
+pub struct CachedProvider {
+	inner: Box<dyn LlmProvider>,
+	dir: PathBuf,
+	model: String,
+	embedding_model: String,
+	// Hash of the model and canonicalization prompt, precomputed since the prompt is long
+	canonicalize_namespace: String,
+}
+
+impl CachedProvider {
+	pub fn new(inner: Box<dyn LlmProvider>, dir: impl Into<PathBuf>, model: &str, embedding_model: &str, prompt: &str) -> Self {
+		CachedProvider {
+			inner,
+			dir: dir.into(),
+			model: model.to_string(),
+			embedding_model: embedding_model.to_string(),
+			canonicalize_namespace: content_hash(model, prompt),
+		}
+	}
+
+	fn entry_path(&self, kind: &str, namespace: &str, input: &str) -> PathBuf {
+		self.dir.join(kind).join(format!("{}.json", content_hash(namespace, input)))
+	}
+
+	fn cached_embedding(&self, text: &str) -> Option<Vec<f32>> {
+		let cached = read_entry(&self.entry_path("embeddings", &self.embedding_model, text))?;
+		serde_json::from_str(&cached).ok()
+	}
+
+	fn store_embedding(&self, text: &str, embedding: &[f32]) {
+		if let Ok(serialized) = serde_json::to_string(embedding) {
+			write_entry(&self.entry_path("embeddings", &self.embedding_model, text), &serialized);
+		}
+	}
+}
+
+// Hex encoded SHA-256 of the namespace and input, separated so ("ab", "c") and ("a", "bc") differ
+pub fn content_hash(namespace: &str, input: &str) -> String {
+	let mut hasher = Sha256::new();
+	hasher.update(namespace.as_bytes());
+	hasher.update([0u8]);
+	hasher.update(input.as_bytes());
+	hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_json(text: &str) -> bool {
+	serde_json::from_str::<serde_json::Value>(text).is_ok()
+}
+
+fn read_entry(path: &Path) -> Option<String> {
+	std::fs::read_to_string(path).ok()
+}
+
+// A failed write only costs a cache miss next time, so errors are ignored. Writing to a temporary file first
+// keeps concurrent readers from seeing a partial entry.
+fn write_entry(path: &Path, contents: &str) {
+	let Some(parent) = path.parent() else {
+		return;
+	};
+	if std::fs::create_dir_all(parent).is_err() {
+		return;
+	}
+	static NEXT_TMP: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+	let unique = NEXT_TMP.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+	let tmp = path.with_extension(format!("{}.{}.tmp", std::process::id(), unique));
+	if std::fs::write(&tmp, contents).is_ok() && std::fs::rename(&tmp, path).is_err() {
+		let _ = std::fs::remove_file(&tmp);
+	}
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for CachedProvider {
+	async fn canonicalize(&self, code: String) -> Result<String, LlmError> {
+		let path = self.entry_path("canonicalize", &self.canonicalize_namespace, &code);
+		if let Some(cached) = read_entry(&path).filter(|cached| is_json(cached)) {
+			log::debug!("Canonicalization served from {}", path.display());
+			return Ok(cached);
+		}
+		let response = self.inner.canonicalize(code).await?;
+		// A reply that fails to parse is handed on so the caller reports it, but kept out of the cache so the next
+		// run asks again instead of failing on the same file forever
+		if is_json(&response) {
+			write_entry(&path, &response);
+		}
+		Ok(response)
+	}
+
+	async fn complete(&self, prompt: String) -> Result<String, LlmError> {
+		let path = self.entry_path("complete", &self.model, &prompt);
+		if let Some(cached) = read_entry(&path) {
+			return Ok(cached);
+		}
+		let response = self.inner.complete(prompt).await?;
+		write_entry(&path, &response);
+		Ok(response)
+	}
+
+	async fn embed(&self, text: String) -> Result<Vec<f32>, LlmError> {
+		if let Some(cached) = self.cached_embedding(&text) {
+			return Ok(cached);
+		}
+		let embedding = self.inner.embed(text.clone()).await?;
+		self.store_embedding(&text, &embedding);
+		Ok(embedding)
+	}
+
+	// Only the texts missing from the cache are sent, in one batch, then merged back in input order
+	async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+		let mut embeddings: Vec<Option<Vec<f32>>> = texts.iter().map(|text| self.cached_embedding(text)).collect();
+		let missing: Vec<usize> = (0..texts.len()).filter(|&i| embeddings[i].is_none()).collect();
+		if !missing.is_empty() {
+			let fetched = self.inner.embed_batch(missing.iter().map(|&i| texts[i].clone()).collect()).await?;
+			for (&i, embedding) in missing.iter().zip(fetched) {
+				// Empty vectors mean the text was not embedded, they must not be cached
+				if !embedding.is_empty() {
+					self.store_embedding(&texts[i], &embedding);
+				}
+				embeddings[i] = Some(embedding);
+			}
+		}
+		Ok(embeddings.into_iter().map(Option::unwrap_or_default).collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+
+	// Counts how often the wrapped provider is actually reached
+	struct CountingProvider {
+		calls: Arc<AtomicUsize>,
+	}
+
+	#[async_trait::async_trait]
+	impl LlmProvider for CountingProvider {
+		async fn canonicalize(&self, code: String) -> Result<String, LlmError> {
+			self.calls.fetch_add(1, Ordering::SeqCst);
+			if code == "garbled" {
+				return Ok("not json".to_string());
+			}
+			Ok(format!("{{\"echo\": {:?}}}", code))
+		}
+
+		async fn embed(&self, text: String) -> Result<Vec<f32>, LlmError> {
+			self.calls.fetch_add(1, Ordering::SeqCst);
+			Ok(vec![text.len() as f32, 1.0])
+		}
+	}
+
+	#[test]
+	fn second_request_is_served_from_disk() {
+		let dir = std::env::temp_dir().join(format!("eve_cache_test_{}", std::process::id()));
+		let calls = Arc::new(AtomicUsize::new(0));
+		let provider = CachedProvider::new(Box::new(CountingProvider { calls: Arc::clone(&calls) }), &dir, "model", "embedder", "prompt");
+		let runtime = crate::llm::runtime();
+
+		let first = runtime.block_on(provider.canonicalize("fn main() {}".to_string())).unwrap();
+		let second = runtime.block_on(provider.canonicalize("fn main() {}".to_string())).unwrap();
+		assert_eq!(first, second);
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+		let batch = vec!["abc".to_string(), "de".to_string()];
+		let embedded = runtime.block_on(provider.embed_batch(batch.clone())).unwrap();
+		let again = runtime.block_on(provider.embed_batch(batch)).unwrap();
+		assert_eq!(embedded, vec![vec![3.0, 1.0], vec![2.0, 1.0]]);
+		assert_eq!(embedded, again);
+		assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+		// A different prompt must not reuse the canonicalization, embeddings stay shared
+		let reworded = CachedProvider::new(Box::new(CountingProvider { calls: Arc::clone(&calls) }), &dir, "model", "embedder", "other prompt");
+		runtime.block_on(reworded.canonicalize("fn main() {}".to_string())).unwrap();
+		runtime.block_on(reworded.embed("abc".to_string())).unwrap();
+		assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+		// Replies that are not JSON are returned but never cached
+		runtime.block_on(provider.canonicalize("garbled".to_string())).unwrap();
+		runtime.block_on(provider.canonicalize("garbled".to_string())).unwrap();
+		assert_eq!(calls.load(Ordering::SeqCst), 6);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
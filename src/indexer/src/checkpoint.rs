@@ -0,0 +1,339 @@
+use crate::{cache, indexer, llm};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+// Periodic snapshots of a running index, so a crash or Ctrl-C late in a long run only loses the files since the last
+// snapshot. Indexing again with the same checkpoint skips every file it already holds.
+
+// Default location of the checkpoint of `root`, relative to the working directory. Named after the canonical root, so
+// indexing another directory from the same place never resumes from its checkpoint.
+pub fn default_path(root: &Path) -> PathBuf {
+	let canonical = std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+	let key = cache::content_hash("checkpoint", &canonical.to_string_lossy());
+	PathBuf::from(format!(".eve_checkpoint_{}.json", &key[..16]))
+}
+// Files indexed between two snapshots when nothing else is configured
+pub const DEFAULT_CHECKPOINT_EVERY: usize = 100;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// Whether a checkpointed run is in progress, Ctrl-C outside of one exits as usual
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Marks a checkpointed run for as long as it is alive
+pub(crate) struct ActiveRun;
+
+impl ActiveRun {
+	pub(crate) fn start() -> Self {
+		ACTIVE.store(true, Ordering::SeqCst);
+		ActiveRun
+	}
+}
+
+impl Drop for ActiveRun {
+	fn drop(&mut self) {
+		ACTIVE.store(false, Ordering::SeqCst);
+	}
+}
+
+// Set once Ctrl-C was pressed with the handler installed, indexing then stops handing out new files
+pub fn interrupted() -> bool {
+	INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Stands in for Ctrl-C in tests. Checkpointed runs all watch the one flag, so tests running them hold TEST_RUNS.
+#[cfg(test)]
+pub(crate) fn set_interrupted(interrupted: bool) {
+	INTERRUPTED.store(interrupted, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+pub(crate) static TEST_RUNS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// Replaces the default Ctrl-C behaviour while a checkpointed run is in progress: the first one lets requests in
+// flight finish and writes a final checkpoint, a second one exits right away. Outside of a run it exits as usual.
+pub fn install_interrupt_handler() {
+	crate::llm::runtime().spawn(async {
+		while tokio::signal::ctrl_c().await.is_ok() {
+			if !ACTIVE.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst) {
+				std::process::exit(130);
+			}
+			eprintln!("\nInterrupted, saving a checkpoint once the files in progress are done. Press Ctrl-C again to quit now.");
+		}
+	});
+}
+
+// Hash of a file's contents and of every setting that shapes its records: the models, how files are chunked, whether
+// the output is schema constrained and the prompt. A resumed file whose hash changed is indexed again. None when the
+// file cannot be read.
+pub fn file_hash(path: &str, config: &llm::LlmConfig) -> Option<String> {
+	let contents = std::fs::read_to_string(path).ok()?;
+	let settings = format!(
+		"{}\0{}\0{:?}\0{}\0{}\0{}",
+		config.model, config.embedding_model, config.chunk_mode, config.max_chunk_tokens, config.structured_output, config.prompt
+	);
+	Some(cache::content_hash(&settings, &contents))
+}
+
+// What a checkpoint holds: the root it was written for, the records indexed so far and the file_hash every file among
+// them was indexed at
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+	pub root: Option<String>,
+	pub codebase: indexer::code_base,
+	pub hashes: HashMap<String, String>,
+}
+
+#[derive(serde::Serialize)]
+struct SnapshotRef<'a> {
+	root: Option<&'a str>,
+	codebase: &'a indexer::code_base,
+	hashes: &'a HashMap<String, String>,
+}
+
+// Only the root of a snapshot, the records are skipped without being built
+#[derive(serde::Deserialize)]
+struct SnapshotRoot {
+	#[serde(default)]
+	root: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+	pub path: PathBuf,
+	// Files indexed between two snapshots, 0 only writes one when indexing is interrupted
+	pub every: usize,
+	// Canonical root of the run, a checkpoint written for another one is not resumed. None accepts any.
+	pub root: Option<String>,
+}
+
+impl Checkpoint {
+	pub fn new(path: impl Into<PathBuf>, every: usize) -> Self {
+		Checkpoint { path: path.into(), every, root: None }
+	}
+
+	// Ties the checkpoint to the directory being indexed
+	pub fn with_root(mut self, root: &Path) -> Self {
+		let canonical = std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+		self.root = Some(canonical.to_string_lossy().into_owned());
+		self
+	}
+
+	// The records of an earlier run, None when there is no checkpoint yet. Failed files are not kept, resuming tries
+	// them again. A checkpoint written for another root is an InvalidData error.
+	pub fn load(&self) -> std::io::Result<Option<Snapshot>> {
+		let contents = match std::fs::read_to_string(&self.path) {
+			Ok(contents) => contents,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err),
+		};
+		let mut snapshot: Snapshot = serde_json::from_str(&contents)?;
+		self.check_root(snapshot.root.as_deref())?;
+		snapshot.codebase.failures.clear();
+		Ok(Some(snapshot))
+	}
+
+	// Fails when the checkpoint on disk belongs to another root, without loading its records. Fine when there is
+	// none yet.
+	pub fn ensure_same_root(&self) -> std::io::Result<()> {
+		let file = match std::fs::File::open(&self.path) {
+			Ok(file) => file,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+			Err(err) => return Err(err),
+		};
+		let saved: SnapshotRoot = serde_json::from_reader(std::io::BufReader::new(file))?;
+		self.check_root(saved.root.as_deref())
+	}
+
+	fn check_root(&self, saved: Option<&str>) -> std::io::Result<()> {
+		match &self.root {
+			Some(root) if saved != Some(root.as_str()) => Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("{} was written for {}, not {}", self.path.display(), saved.unwrap_or("an unknown root"), root),
+			)),
+			_ => Ok(()),
+		}
+	}
+
+	// Written to a temporary file first and renamed over the old checkpoint, so a crash while saving leaves the
+	// previous one intact. `hashes` maps indexed paths to their file_hash.
+	pub fn save(&self, codebase: &indexer::code_base, hashes: &HashMap<String, String>) -> std::io::Result<()> {
+		let tmp = self.path.with_extension(format!("{}.tmp", std::process::id()));
+		let file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+		serde_json::to_writer(file, &SnapshotRef { root: self.root.as_deref(), codebase, hashes })?;
+		std::fs::rename(&tmp, &self.path)
+	}
+
+	// Called once a run completed, the next one starts from scratch
+	pub fn remove(&self) -> std::io::Result<()> {
+		match std::fs::remove_file(&self.path) {
+			Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn exists(&self) -> bool {
+		Path::new(&self.path).is_file()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	// Five small Python files, each defining one class with one method, and a provider answering for all of them
+	fn fixture(name: &str) -> (PathBuf, Vec<String>, crate::mock::MockProvider) {
+		let dir = std::env::temp_dir().join(format!("eve_checkpoint_{}_{}", name, std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let mut files = Vec::new();
+		let mut provider = crate::mock::MockProvider::new();
+		for i in 0..5 {
+			let path = dir.join(format!("file_{}.py", i));
+			std::fs::write(&path, format!("class Holder{i}:\n    def helper_{i}(self):\n        pass\n", i = i)).unwrap();
+			let response = format!(
+				r#"{{"General Information": {{"Language": "Python", "Description": "Holds {i}."}},
+				"Objects": [{{"name": "Holder{i}", "type": "class", "morphisms": ["helper_{i}"], "Dependencies": [], "description": "Holder {i}."}}],
+				"Morphisms": [{{"name": "helper_{i}", "type": "method", "Dependencies": [], "description": "Helps {i}."}}]}}"#,
+				i = i
+			);
+			provider = provider.with_response(&format!("helper_{}", i), &response);
+			files.push(path.to_str().unwrap().to_string());
+		}
+		(dir, files, provider)
+	}
+
+	// What the checkpoint on disk held each time a file was reported, as (indexed paths, hashed paths)
+	struct SnapshotReporter {
+		checkpoint: Checkpoint,
+		seen: Mutex<Vec<(Vec<String>, Vec<String>)>>,
+		// Presses "Ctrl-C" once this many files were reported
+		interrupt_after: Option<usize>,
+	}
+
+	impl indexer::ProgressReporter for SnapshotReporter {
+		fn on_file_indexed(&self, done: usize, _total: usize) {
+			let snapshot = self.checkpoint.load().unwrap().unwrap_or_default();
+			let mut hashed: Vec<String> = snapshot.hashes.into_keys().collect();
+			hashed.sort();
+			let indexed = snapshot.codebase.files.into_iter().map(|file| file.path).collect();
+			self.seen.lock().unwrap().push((indexed, hashed));
+			if self.interrupt_after == Some(done) {
+				set_interrupted(true);
+			}
+		}
+
+		fn on_file_failed(&self, path: &str, err: &str) {
+			panic!("{} failed: {}", path, err);
+		}
+
+		fn on_file_skipped(&self, _path: &str, _reason: &indexer::SkipReason) {}
+
+		fn on_embeddings_failed(&self, _failed: usize, _total: usize) {}
+	}
+
+	#[test]
+	fn checkpoint_is_saved_every_few_files() {
+		let _runs = TEST_RUNS.lock().unwrap_or_else(|err| err.into_inner());
+		set_interrupted(false);
+		let (dir, files, provider) = fixture("every");
+		let checkpoint = Checkpoint::new(dir.join("checkpoint.json"), 2);
+		let reporter = Arc::new(SnapshotReporter { checkpoint: checkpoint.clone(), seen: Mutex::new(Vec::new()), interrupt_after: None });
+		let config = llm::LlmConfig { max_concurrent_requests: 1, ..Default::default() };
+		let code_base = indexer::index_files_with_checkpoint(&files, &provider, &config, reporter.clone(), Some(&checkpoint));
+		assert_eq!(code_base.files.len(), 5);
+		// A snapshot is written right after the second and fourth file were reported
+		let seen = reporter.seen.lock().unwrap();
+		let expected = |n: usize| (files[..n].to_vec(), files[..n].to_vec());
+		assert_eq!(*seen, vec![expected(0), expected(0), expected(2), expected(2), expected(4)]);
+		assert!(!checkpoint.exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn interrupted_run_flushes_a_final_checkpoint() {
+		let _runs = TEST_RUNS.lock().unwrap_or_else(|err| err.into_inner());
+		set_interrupted(false);
+		let (dir, files, provider) = fixture("interrupted");
+		// No periodic snapshots, only the one written on the interrupt
+		let checkpoint = Checkpoint::new(dir.join("checkpoint.json"), 0);
+		let reporter = Arc::new(SnapshotReporter { checkpoint: checkpoint.clone(), seen: Mutex::new(Vec::new()), interrupt_after: Some(1) });
+		let config = llm::LlmConfig { max_concurrent_requests: 1, ..Default::default() };
+		let code_base = indexer::index_files_with_checkpoint(&files, &provider, &config, reporter, Some(&checkpoint));
+		set_interrupted(false);
+		// Files already in flight are finished, the rest are left for the next run
+		let indexed: Vec<String> = code_base.files.iter().map(|file| file.path.clone()).collect();
+		assert!(!indexed.is_empty() && indexed.len() < files.len());
+		assert_eq!(indexed, files[..indexed.len()].to_vec());
+		// The partial result is linked like a complete one
+		assert!(code_base.morphisms.iter().all(|morph| morph.owner_object.as_deref() == Some(morph.name.replace("helper_", "Holder").as_str())));
+		let saved = checkpoint.load().unwrap().unwrap();
+		let saved_paths: Vec<String> = saved.codebase.files.iter().map(|file| file.path.clone()).collect();
+		assert_eq!(saved_paths, indexed);
+		assert_eq!(saved.codebase.morphisms.len(), indexed.len());
+		for path in &indexed {
+			assert_eq!(saved.hashes.get(path), file_hash(path, &config).as_ref());
+		}
+		assert_eq!(saved.hashes.len(), indexed.len());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn checkpoint_round_trips_records_but_not_failures() {
+		let path = std::env::temp_dir().join(format!("eve_checkpoint_test_{}.json", std::process::id()));
+		let checkpoint = Checkpoint::new(&path, 10);
+		assert!(checkpoint.load().unwrap().is_none());
+		let codebase = indexer::code_base {
+			files: vec![llm::File { path: "a.rs".to_string(), embedding: vec![0.5, 1.0], ..Default::default() }],
+			morphisms: vec![llm::Morphism { name: "run".to_string(), file_path: "a.rs".to_string(), start_line: 3, ..Default::default() }],
+			failures: vec![("b.rs".to_string(), "timed out".to_string())],
+			..Default::default()
+		};
+		let hashes = HashMap::from([("a.rs".to_string(), "abc".to_string())]);
+		checkpoint.save(&codebase, &hashes).unwrap();
+		let loaded = checkpoint.load().unwrap().unwrap();
+		assert_eq!(loaded.codebase.files[0].embedding, vec![0.5, 1.0]);
+		assert_eq!(loaded.codebase.morphisms[0].start_line, 3);
+		assert!(loaded.codebase.failures.is_empty());
+		assert_eq!(loaded.hashes, hashes);
+		checkpoint.remove().unwrap();
+		assert!(!checkpoint.exists());
+		checkpoint.remove().unwrap();
+	}
+
+	#[test]
+	fn checkpoint_of_another_root_is_not_resumed() {
+		let dir = std::env::temp_dir().join(format!("eve_checkpoint_roots_{}", std::process::id()));
+		for sub in ["a", "b"] {
+			std::fs::create_dir_all(dir.join(sub)).unwrap();
+		}
+		let path = dir.join("checkpoint.json");
+		let written = Checkpoint::new(&path, 10).with_root(&dir.join("a"));
+		written.save(&indexer::code_base::default(), &HashMap::new()).unwrap();
+		// The same root spelled differently still matches
+		let same = Checkpoint::new(&path, 10).with_root(&dir.join("b/../a"));
+		assert!(same.ensure_same_root().is_ok());
+		assert!(same.load().unwrap().is_some());
+		let other = Checkpoint::new(&path, 10).with_root(&dir.join("b"));
+		assert_eq!(other.ensure_same_root().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+		assert_eq!(other.load().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+		assert_ne!(default_path(&dir.join("a")), default_path(&dir.join("b")));
+		assert_eq!(default_path(&dir.join("a")), default_path(&dir.join("b/../a")));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn file_hash_changes_with_chunking_and_structured_output() {
+		let path = std::env::temp_dir().join(format!("eve_checkpoint_hash_{}.py", std::process::id()));
+		std::fs::write(&path, "def run():\n    pass\n").unwrap();
+		let path_str = path.to_str().unwrap();
+		let config = llm::LlmConfig::default();
+		let base = file_hash(path_str, &config).unwrap();
+		let smaller_chunks = llm::LlmConfig { max_chunk_tokens: config.max_chunk_tokens / 2, ..config.clone() };
+		let free_form = llm::LlmConfig { structured_output: !config.structured_output, ..config.clone() };
+		assert_ne!(file_hash(path_str, &smaller_chunks).unwrap(), base);
+		assert_ne!(file_hash(path_str, &free_form).unwrap(), base);
+		assert_eq!(file_hash(path_str, &config).unwrap(), base);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,305 @@
+use crate::indexer;
+use std::collections::{HashMap, HashSet};
+// Assembles the dependency lists extracted by the LLM into a graph over objects and morphisms
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+	Object,
+	Morphism,
+	// A dependency that does not match anything in the index, e.g. an external crate or a parameter
+	External,
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+	pub name: String,
+	pub kind: NodeKind,
+	// Empty for external nodes
+	pub file_path: String,
+}
+
+// Edges point from the dependent node to the node it depends on, both given as indices into `nodes`
+#[derive(Debug, Clone, Default)]
+pub struct DependencyGraph {
+	pub nodes: Vec<Node>,
+	pub edges: Vec<(usize, usize)>,
+}
+
+// Every object and morphism becomes a node. A dependency name resolves to a definition in the same file when there
+// is one, then to any definition with that name (objects before morphisms), and otherwise to a shared external leaf.
+pub fn build_dependency_graph(code_base: &indexer::code_base) -> DependencyGraph {
+	let mut graph = DependencyGraph::default();
+	let mut by_name: HashMap<&str, Vec<usize>> = HashMap::new();
+	for obj in code_base.objects.iter() {
+		by_name.entry(obj.name.as_str()).or_default().push(graph.nodes.len());
+		graph.nodes.push(Node { name: obj.name.clone(), kind: NodeKind::Object, file_path: obj.file_path.clone() });
+	}
+	for morph in code_base.morphisms.iter() {
+		by_name.entry(morph.name.as_str()).or_default().push(graph.nodes.len());
+		graph.nodes.push(Node { name: morph.name.clone(), kind: NodeKind::Morphism, file_path: morph.file_path.clone() });
+	}
+
+	let dependents = code_base.objects
+		.iter()
+		.map(|obj| (&obj.file_path, &obj.dependencies))
+		.chain(code_base.morphisms.iter().map(|morph| (&morph.file_path, &morph.dependencies)));
+	let mut externals: HashMap<String, usize> = HashMap::new();
+	let mut edges = Vec::new();
+	// Kept next to `edges` so duplicate dependencies are dropped without scanning every edge
+	let mut seen: HashSet<(usize, usize)> = HashSet::new();
+	for (from, (file_path, dependencies)) in dependents.enumerate() {
+		for dependency in dependencies.iter() {
+			let resolved = by_name.get(dependency.as_str()).and_then(|candidates| {
+				candidates
+					.iter()
+					.copied()
+					.find(|&i| &graph.nodes[i].file_path == file_path)
+					.or_else(|| candidates.first().copied())
+			});
+			let to = match resolved {
+				Some(to) => to,
+				None => *externals.entry(dependency.clone()).or_insert_with(|| {
+					graph.nodes.push(Node { name: dependency.clone(), kind: NodeKind::External, file_path: String::new() });
+					graph.nodes.len() - 1
+				}),
+			};
+			if seen.insert((from, to)) {
+				edges.push((from, to));
+			}
+		}
+	}
+	graph.edges = edges;
+	graph
+}
+
+fn escape_label(label: &str) -> String {
+	label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl DependencyGraph {
+	// Renders the graph in Graphviz DOT. Objects are boxes, morphisms ellipses and external leaves dashed grey.
+	pub fn to_dot(&self) -> String {
+		let mut dot = String::from("digraph dependencies {\n");
+		for (i, node) in self.nodes.iter().enumerate() {
+			let style = match node.kind {
+				NodeKind::Object => "shape=box",
+				NodeKind::Morphism => "shape=ellipse",
+				NodeKind::External => "shape=ellipse, style=dashed, color=gray, fontcolor=gray",
+			};
+			dot.push_str(&format!("    n{} [label=\"{}\", {}];\n", i, escape_label(&node.name), style));
+		}
+		for (from, to) in self.edges.iter() {
+			dot.push_str(&format!("    n{} -> n{};\n", from, to));
+		}
+		dot.push_str("}\n");
+		dot
+	}
+}
+
+// Tarjan's strongly connected components over an adjacency list. The depth first search keeps its own stack, so
+// dependency chains thousands of levels deep do not overflow the thread's.
+struct Tarjan<'a> {
+	adjacency: &'a [Vec<usize>],
+	index: Vec<Option<usize>>,
+	lowlink: Vec<usize>,
+	on_stack: Vec<bool>,
+	stack: Vec<usize>,
+	next_index: usize,
+	components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+	fn enter(&mut self, v: usize) {
+		self.index[v] = Some(self.next_index);
+		self.lowlink[v] = self.next_index;
+		self.next_index += 1;
+		self.stack.push(v);
+		self.on_stack[v] = true;
+	}
+
+	fn visit(&mut self, root: usize) {
+		// Nodes being visited with the position of the next edge to follow, what recursion would keep in its frames
+		let mut calls: Vec<(usize, usize)> = vec![(root, 0)];
+		self.enter(root);
+		while let Some(&(v, next)) = calls.last() {
+			if let Some(&w) = self.adjacency[v].get(next) {
+				if let Some(call) = calls.last_mut() {
+					call.1 += 1;
+				}
+				match self.index[w] {
+					None => {
+						self.enter(w);
+						calls.push((w, 0));
+					}
+					Some(w_index) if self.on_stack[w] => self.lowlink[v] = self.lowlink[v].min(w_index),
+					Some(_) => {}
+				}
+				continue;
+			}
+			// Every edge of v is done, return to its caller
+			calls.pop();
+			if let Some(&(parent, _)) = calls.last() {
+				self.lowlink[parent] = self.lowlink[parent].min(self.lowlink[v]);
+			}
+			if Some(self.lowlink[v]) == self.index[v] {
+				let mut component = Vec::new();
+				while let Some(w) = self.stack.pop() {
+					self.on_stack[w] = false;
+					component.push(w);
+					if w == v {
+						break;
+					}
+				}
+				self.components.push(component);
+			}
+		}
+	}
+}
+
+// Shortest path start -> ... -> start that stays inside `component`, found breadth first
+fn cycle_through(adjacency: &[Vec<usize>], component: &[usize], start: usize) -> Vec<usize> {
+	let mut parent: HashMap<usize, usize> = HashMap::new();
+	let mut queue = std::collections::VecDeque::from([start]);
+	while let Some(v) = queue.pop_front() {
+		for &w in adjacency[v].iter() {
+			if w == start {
+				let mut path = vec![v];
+				let mut current = v;
+				while current != start {
+					current = parent[&current];
+					path.push(current);
+				}
+				path.reverse();
+				return path;
+			}
+			if component.contains(&w) && !parent.contains_key(&w) {
+				parent.insert(w, v);
+				queue.push_back(w);
+			}
+		}
+	}
+	vec![start]
+}
+
+// Reports circular dependencies between objects, one ordered cycle of object names per group of mutually dependent
+// objects (an object depending on itself counts too). Dependencies on morphisms or on anything outside the index
+// are ignored.
+pub fn find_cycles(code_base: &indexer::code_base) -> Vec<Vec<String>> {
+	let graph = build_dependency_graph(code_base);
+	// Objects are always the first nodes of the graph
+	let num_objects = code_base.objects.len();
+	let mut adjacency: Vec<Vec<usize>> = vec![Vec::new(); num_objects];
+	for &(from, to) in graph.edges.iter() {
+		if from < num_objects && to < num_objects {
+			adjacency[from].push(to);
+		}
+	}
+	let mut tarjan = Tarjan {
+		adjacency: &adjacency,
+		index: vec![None; num_objects],
+		lowlink: vec![0; num_objects],
+		on_stack: vec![false; num_objects],
+		stack: Vec::new(),
+		next_index: 0,
+		components: Vec::new(),
+	};
+	for v in 0..num_objects {
+		if tarjan.index[v].is_none() {
+			tarjan.visit(v);
+		}
+	}
+	let mut cycles: Vec<Vec<usize>> = tarjan.components
+		.into_iter()
+		.filter(|component| component.len() > 1 || adjacency[component[0]].contains(&component[0]))
+		.map(|component| {
+			let start = *component.iter().min().unwrap();
+			cycle_through(&adjacency, &component, start)
+		})
+		.collect();
+	cycles.sort();
+	cycles
+		.into_iter()
+		.map(|cycle| cycle.into_iter().map(|i| graph.nodes[i].name.clone()).collect())
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::llm;
+
+	#[test]
+	fn dependencies_resolve_to_nodes_or_external_leaves() {
+		let code_base = indexer::code_base {
+			objects: vec![
+				llm::Object { name: "Animal".to_string(), file_path: "a.py".to_string(), ..Default::default() },
+				llm::Object {
+					name: "Dog".to_string(),
+					file_path: "a.py".to_string(),
+					dependencies: vec!["Animal".to_string()],
+					..Default::default()
+				},
+			],
+			morphisms: vec![llm::Morphism {
+				name: "fetch_inst".to_string(),
+				file_path: "a.py".to_string(),
+				dependencies: vec!["requests".to_string(), "Dog".to_string()],
+				..Default::default()
+			}],
+			..Default::default()
+		};
+		let graph = build_dependency_graph(&code_base);
+		assert_eq!(graph.nodes.len(), 4);
+		assert_eq!(graph.nodes[3].kind, NodeKind::External);
+		assert_eq!(graph.nodes[3].name, "requests");
+		assert_eq!(graph.edges, vec![(1, 0), (2, 3), (2, 1)]);
+		let dot = graph.to_dot();
+		assert!(dot.starts_with("digraph dependencies {"));
+		assert!(dot.contains("n3 [label=\"requests\", shape=ellipse, style=dashed"));
+		assert!(dot.contains("n1 -> n0;"));
+	}
+
+	#[test]
+	fn find_cycles_handles_deep_dependency_chains() {
+		// n0 -> n1 -> ... -> n99999, with only the last two depending on each other
+		let count = 100_000;
+		let objects = (0..count)
+			.map(|i| llm::Object {
+				name: format!("n{}", i),
+				file_path: "deep.rs".to_string(),
+				dependencies: vec![format!("n{}", if i + 1 < count { i + 1 } else { i - 1 })],
+				..Default::default()
+			})
+			.collect();
+		let code_base = indexer::code_base { objects, ..Default::default() };
+		assert_eq!(find_cycles(&code_base), vec![vec!["n99998".to_string(), "n99999".to_string()]]);
+	}
+
+	#[test]
+	fn find_cycles_reports_object_cycles_only() {
+		let object = |name: &str, dependencies: &[&str]| llm::Object {
+			name: name.to_string(),
+			file_path: "lib.rs".to_string(),
+			dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+			..Default::default()
+		};
+		let code_base = indexer::code_base {
+			objects: vec![
+				object("A", &["B", "serde"]),
+				object("B", &["C"]),
+				object("C", &["A"]),
+				object("D", &["D"]),
+				object("E", &["A", "helper"]),
+			],
+			morphisms: vec![llm::Morphism {
+				name: "helper".to_string(),
+				file_path: "lib.rs".to_string(),
+				dependencies: vec!["E".to_string()],
+				..Default::default()
+			}],
+			..Default::default()
+		};
+		let cycles = find_cycles(&code_base);
+		assert_eq!(cycles, vec![vec!["A", "B", "C"], vec!["D"]]);
+	}
+}
@@ -1,134 +1,2240 @@
-use crate::llm;
-use std::fmt;
+use crate::{ann, checkpoint, llm};
+use rayon::prelude::*;
 // This is the rust code for the indexer module. We simply loop through all the files and canonicalize them
-#[derive(Default, Debug, Clone)]
+#[allow(non_camel_case_types)]
+// Serialized for checkpoints, see checkpoint::Checkpoint
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct code_base {
 	pub files: Vec<llm::File>,
 	pub objects: Vec<llm::Object>,
 	pub morphisms: Vec<llm::Morphism>,
+	// Set by normalize_embeddings once every stored embedding has unit length
+	pub normalized: bool,
+	// Approximate index searched instead of every embedding once set, see build_ann_index. Cheap to rebuild, so it
+	// is never saved.
+	#[serde(skip)]
+	pub ann_index: Option<ann::AnnIndex>,
+	// Overview of the whole repository, empty until summarize runs
+	pub summary: String,
+	// (path, error) of every file that could not be indexed, in input order
+	pub failures: Vec<(String, String)>,
+}
+
+impl code_base {
+	// Scales every stored embedding to unit length so searches only need a dot product per item
+	pub fn normalize_embeddings(&mut self) {
+		for file in self.files.iter_mut() {
+			normalize(&mut file.embedding);
+		}
+		for obj in self.objects.iter_mut() {
+			normalize(&mut obj.embedding);
+		}
+		for morph in self.morphisms.iter_mut() {
+			normalize(&mut morph.embedding);
+		}
+		self.normalized = true;
+	}
+
+	// Builds an approximate nearest neighbour index over the current embeddings. Store it in ann_index to make
+	// searches scan only the closest clusters; leaving ann_index unset keeps exact search.
+	pub fn build_ann_index(&self) -> ann::AnnIndex {
+		ann::AnnIndex::build(self)
+	}
+
+	// Resolves the morphism name lists on every object into owner_object back-references. Only objects from the
+	// morphism's own file are considered, and when several of them declare the same name the one whose line range
+	// encloses the morphism wins, falling back to declaration order.
+	pub fn link_morphisms(&mut self) {
+		let mut by_file: std::collections::HashMap<&str, Vec<usize>> = std::collections::HashMap::new();
+		for (i, obj) in self.objects.iter().enumerate() {
+			by_file.entry(obj.file_path.as_str()).or_default().push(i);
+		}
+		let mut claimed: std::collections::HashSet<(usize, &str)> = std::collections::HashSet::new();
+		let mut owners: Vec<Option<usize>> = Vec::with_capacity(self.morphisms.len());
+		for morph in self.morphisms.iter() {
+			let candidates: Vec<usize> = by_file
+				.get(morph.file_path.as_str())
+				.into_iter()
+				.flatten()
+				.copied()
+				.filter(|&i| self.objects[i].morphisms.contains(&morph.name))
+				.collect();
+			let enclosing = candidates
+				.iter()
+				.copied()
+				.filter(|&i| {
+					let obj = &self.objects[i];
+					morph.start_line > 0 && obj.start_line <= morph.start_line && morph.start_line <= obj.end_line
+				})
+				.min_by_key(|&i| self.objects[i].end_line - self.objects[i].start_line);
+			let owner = enclosing.or_else(|| {
+				candidates.iter().copied().find(|&i| !claimed.contains(&(i, morph.name.as_str())))
+			});
+			if let Some(i) = owner {
+				claimed.insert((i, morph.name.as_str()));
+			}
+			owners.push(owner);
+		}
+		for (morph, owner) in self.morphisms.iter_mut().zip(owners) {
+			morph.owner_object = owner.map(|i| self.objects[i].name.clone());
+		}
+	}
+
+	// Embeds again every record whose embedding failed during indexing, in batches of llm::MAX_EMBEDDING_BATCH.
+	// Returns how many are still missing afterwards.
+	pub fn retry_failed_embeddings(&mut self, provider: &dyn llm::LlmProvider) -> usize {
+		let mut targets: Vec<(&mut Vec<f32>, &mut bool)> = Vec::new();
+		let mut descriptions = Vec::new();
+		for file in self.files.iter_mut().filter(|file| file.embedding_failed) {
+			descriptions.push(file.description.clone());
+			targets.push((&mut file.embedding, &mut file.embedding_failed));
+		}
+		for obj in self.objects.iter_mut().filter(|obj| obj.embedding_failed) {
+			descriptions.push(obj.description.clone());
+			targets.push((&mut obj.embedding, &mut obj.embedding_failed));
+		}
+		for morph in self.morphisms.iter_mut().filter(|morph| morph.embedding_failed) {
+			descriptions.push(morph.description.clone());
+			targets.push((&mut morph.embedding, &mut morph.embedding_failed));
+		}
+		if targets.is_empty() {
+			return 0;
+		}
+		// New embeddings are not in the clusters, the index has to be rebuilt
+		self.ann_index = None;
+		let mut remaining = targets.len();
+		let mut targets = targets.into_iter();
+		for batch in descriptions.chunks(llm::MAX_EMBEDDING_BATCH) {
+			let batch_targets: Vec<_> = targets.by_ref().take(batch.len()).collect();
+			// A failed batch stays flagged, the next retry gets another go at it
+			let embeddings = match llm::runtime().block_on(provider.embed_batch(batch.to_vec())) {
+				Ok(embeddings) => embeddings,
+				Err(err) => {
+					log::warn!("Failed to embed {} descriptions again: {}", batch.len(), err);
+					continue;
+				}
+			};
+			for ((embedding, failed), retried) in batch_targets.into_iter().zip(embeddings) {
+				if retried.is_empty() {
+					continue;
+				}
+				*embedding = retried;
+				if self.normalized {
+					normalize(embedding);
+				}
+				*failed = false;
+				remaining -= 1;
+			}
+		}
+		remaining
+	}
+
+	// Notes that a file could not be indexed, replacing an earlier failure of the same file
+	pub fn record_failure(&mut self, path: &str, err: &str) {
+		self.failures.retain(|(failed, _)| failed != path);
+		self.failures.push((path.to_string(), err.to_string()));
+	}
+
+	// "Indexed 880/900 files, 20 failed:" followed by one line per failed file, None when nothing failed
+	pub fn failure_report(&self) -> Option<String> {
+		if self.failures.is_empty() {
+			return None;
+		}
+		let attempted = self.files.len() + self.failures.len();
+		let mut report = format!("Indexed {}/{} files, {} failed:\n", self.files.len(), attempted, self.failures.len());
+		for (path, err) in self.failures.iter() {
+			report.push_str(&format!("  {}: {}\n", path, err));
+		}
+		Some(report)
+	}
+
+	// Number of records without an embedding, out of every file, object and morphism in the index
+	pub fn embedding_failures(&self) -> (usize, usize) {
+		let failed = self.files.iter().filter(|file| file.embedding_failed).count()
+			+ self.objects.iter().filter(|obj| obj.embedding_failed).count()
+			+ self.morphisms.iter().filter(|morph| morph.embedding_failed).count();
+		(failed, self.files.len() + self.objects.len() + self.morphisms.len())
+	}
+
+	// Drops a file and everything extracted from it, e.g. after it was deleted
+	pub fn remove_file(&mut self, path: &str) {
+		self.ann_index = None;
+		self.failures.retain(|(failed, _)| failed != path);
+		self.files.retain(|file| file.path != path);
+		self.objects.retain(|obj| obj.file_path != path);
+		self.morphisms.retain(|morph| morph.file_path != path);
+	}
+
+	// Collapses objects, and morphisms of the same owner, that share a name and whose embeddings have a cosine
+	// similarity of at least `threshold`, e.g. a struct copied or re-exported across files. The first occurrence is
+	// kept and lists the files of the others. Records without an embedding are never collapsed. Returns how many
+	// records were removed.
+	pub fn dedup(&mut self, threshold: f32) -> usize {
+		let before = self.objects.len() + self.morphisms.len();
+		self.objects = dedup_records(
+			std::mem::take(&mut self.objects),
+			threshold,
+			|obj| (obj.name.clone(), obj.embedding.as_slice()),
+			|kept, duplicate| absorb_duplicate(&mut kept.duplicate_file_paths, &kept.file_path, duplicate.file_path, duplicate.duplicate_file_paths),
+		);
+		self.morphisms = dedup_records(
+			std::mem::take(&mut self.morphisms),
+			threshold,
+			|morph| (format!("{}\0{}", morph.owner_object.as_deref().unwrap_or(""), morph.name), morph.embedding.as_slice()),
+			|kept, duplicate| absorb_duplicate(&mut kept.duplicate_file_paths, &kept.file_path, duplicate.file_path, duplicate.duplicate_file_paths),
+		);
+		let removed = before - self.objects.len() - self.morphisms.len();
+		if removed > 0 {
+			self.ann_index = None;
+		}
+		removed
+	}
+
+	// One line per file with its language and the description extracted from it, in index order. This is what
+	// summarize condenses, and the summary itself when no LLM is asked.
+	pub fn file_overview(&self) -> String {
+		let mut overview = String::new();
+		for file in self.files.iter() {
+			let language = if file.language.is_empty() { "unknown" } else { file.language.as_str() };
+			overview.push_str(&format!("{} ({}): {}\n", file.path, language, file.description.trim()));
+		}
+		overview
+	}
+
+	// Fills in `summary`. With a provider the file descriptions are condensed by the LLM into a short description of
+	// the repository, one extra request. Without one, or when that request fails, the file overview is used as is.
+	pub async fn summarize_async(&mut self, provider: Option<&dyn llm::LlmProvider>) {
+		let overview = self.file_overview();
+		self.summary = match provider {
+			Some(provider) => match llm::summarize_overview(&overview, provider).await {
+				Ok(summary) => summary,
+				Err(err) => {
+					log::warn!("Failed to summarize the codebase, listing file descriptions instead: {}", err);
+					overview
+				}
+			},
+			None => overview,
+		};
+	}
+
+	pub fn summarize(&mut self, provider: Option<&dyn llm::LlmProvider>) {
+		llm::runtime().block_on(self.summarize_async(provider))
+	}
+
+	// Aggregates what was extracted per file into an overview of the whole index
+	pub fn stats(&self) -> CodebaseStats {
+		let mut languages: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+		for file in self.files.iter() {
+			let language = if file.language.is_empty() { "unknown" } else { file.language.as_str() };
+			*languages.entry(language.to_string()).or_default() += 1;
+		}
+		// Every dependency list naming an object counts as one inbound reference to it
+		let object_names: std::collections::HashSet<&str> = self.objects.iter().map(|obj| obj.name.as_str()).collect();
+		let mut dependents: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+		let dependencies = self.objects
+			.iter()
+			.map(|obj| &obj.dependencies)
+			.chain(self.morphisms.iter().map(|morph| &morph.dependencies));
+		for dependency in dependencies.flatten() {
+			if object_names.contains(dependency.as_str()) {
+				*dependents.entry(dependency.clone()).or_default() += 1;
+			}
+		}
+		// Highest count first, ties by name so the report is stable
+		let by_count = |a: &(String, usize), b: &(String, usize)| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0));
+		let mut languages: Vec<(String, usize)> = languages.into_iter().collect();
+		let mut dependents: Vec<(String, usize)> = dependents.into_iter().collect();
+		languages.sort_by(by_count);
+		dependents.sort_by(by_count);
+		dependents.truncate(MOST_DEPENDED_UPON);
+		CodebaseStats {
+			files: self.files.len(),
+			objects: self.objects.len(),
+			morphisms: self.morphisms.len(),
+			total_bytes: self.files.iter().map(|file| file.size).sum(),
+			languages,
+			most_depended_upon: dependents,
+		}
+	}
+
+	// Folds another index into this one, e.g. a re-indexed subdirectory. A file already present under the same path
+	// is replaced, together with every object and morphism extracted from it, so nothing ends up duplicated.
+	pub fn merge(&mut self, mut other: code_base) {
+		// Positions shift and new embeddings arrive, so any approximate index is stale
+		self.ann_index = None;
+		let replaced: std::collections::HashSet<String> = other.files.iter().map(|file| file.path.clone()).collect();
+		self.files.retain(|file| !replaced.contains(&file.path));
+		self.objects.retain(|obj| !replaced.contains(&obj.file_path));
+		self.morphisms.retain(|morph| !replaced.contains(&morph.file_path));
+		self.failures.retain(|(path, _)| !replaced.contains(path));
+		for (path, err) in std::mem::take(&mut other.failures) {
+			self.record_failure(&path, &err);
+		}
+		// Searches pick dot product or cosine for the whole index, so both halves must agree on normalization
+		if self.normalized && !other.normalized {
+			other.normalize_embeddings();
+		}
+		let normalize_self = other.normalized && !self.normalized;
+		self.files.append(&mut other.files);
+		self.objects.append(&mut other.objects);
+		self.morphisms.append(&mut other.morphisms);
+		if normalize_self {
+			self.normalize_embeddings();
+		}
+	}
+}
+// How many objects CodebaseStats lists as most depended upon
+pub const MOST_DEPENDED_UPON: usize = 10;
+
+// Summary returned by code_base::stats, lists are sorted by count, highest first
+#[derive(Debug, Clone, Default)]
+pub struct CodebaseStats {
+	pub files: usize,
+	pub objects: usize,
+	pub morphisms: usize,
+	// Sum of File.size over the index
+	pub total_bytes: u64,
+	pub languages: Vec<(String, usize)>,
+	// Objects with the most inbound dependency references, with the reference count
+	pub most_depended_upon: Vec<(String, usize)>,
+}
+
+impl std::fmt::Display for CodebaseStats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		writeln!(f, "{} files ({} bytes), {} objects, {} morphisms", self.files, self.total_bytes, self.objects, self.morphisms)?;
+		let languages: Vec<String> = self.languages.iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+		writeln!(f, "Languages: {}", languages.join(", "))?;
+		if !self.most_depended_upon.is_empty() {
+			writeln!(f, "Most depended upon:")?;
+			for (name, count) in self.most_depended_upon.iter() {
+				writeln!(f, "  {} ({} references)", name, count)?;
+			}
+		}
+		Ok(())
+	}
 }
+
+#[allow(non_camel_case_types)]
 #[derive(Default, Debug, Clone)]
+// Each hit is paired with its cosine similarity to the query, highest first
 pub struct query_result {
-	pub relevant_files: Vec<llm::File>,
-	pub relevant_objects: Vec<llm::Object>,
-	pub relevant_morphisms: Vec<llm::Morphism>,
+	pub relevant_files: Vec<(llm::File, f32)>,
+	pub relevant_objects: Vec<(llm::Object, f32)>,
+	pub relevant_morphisms: Vec<(llm::Morphism, f32)>,
+}
+
+impl query_result {
+	// Machine-readable form of the hits for editors and scripts. Embeddings are left out, everything else an item
+	// carries is kept along with its score.
+	pub fn to_json(&self) -> serde_json::Value {
+		let files: Vec<serde_json::Value> = self.relevant_files
+			.iter()
+			.map(|(file, score)| serde_json::json!({
+				"score": score,
+				"name": file.name,
+				"path": file.path,
+				"language": file.language,
+				"size": file.size,
+				"description": file.description,
+			}))
+			.collect();
+		let objects: Vec<serde_json::Value> = self.relevant_objects
+			.iter()
+			.map(|(obj, score)| serde_json::json!({
+				"score": score,
+				"name": obj.name,
+				"type": obj.obj_type,
+				"file_path": obj.file_path,
+				"start_line": obj.start_line,
+				"end_line": obj.end_line,
+				"duplicate_file_paths": obj.duplicate_file_paths,
+				"morphisms": obj.morphisms,
+				"dependencies": obj.dependencies,
+				"description": obj.description,
+			}))
+			.collect();
+		let morphisms: Vec<serde_json::Value> = self.relevant_morphisms
+			.iter()
+			.map(|(morph, score)| serde_json::json!({
+				"score": score,
+				"name": morph.name,
+				"type": morph.morph_type,
+				"file_path": morph.file_path,
+				"start_line": morph.start_line,
+				"end_line": morph.end_line,
+				"duplicate_file_paths": morph.duplicate_file_paths,
+				"owner_object": morph.owner_object,
+				"dependencies": morph.dependencies,
+				"description": morph.description,
+			}))
+			.collect();
+		serde_json::json!({ "files": files, "objects": objects, "morphisms": morphisms })
+	}
+}
+
+// Receives progress events from the indexing routine, implementations must be shareable across worker threads
+pub trait ProgressReporter: Send + Sync {
+	// `done` counts every processed file, failed ones included
+	fn on_file_indexed(&self, done: usize, total: usize);
+	fn on_file_failed(&self, path: &str, err: &str);
+	// A file left out by the size or binary check, it does not count towards the total
+	fn on_file_skipped(&self, path: &str, reason: &SkipReason);
+	// Called once at the end of indexing when some descriptions still have no embedding after the retry
+	fn on_embeddings_failed(&self, failed: usize, total: usize);
+}
+
+// Rewrites the progress line in place and ends it after the last file. Write errors are ignored, a broken pipe must
+// not stop indexing.
+fn write_progress(mut out: impl std::io::Write, done: usize, total: usize) {
+	let _ = write!(out, "\rIndexed {}/{} files", done, total);
+	if done >= total {
+		let _ = writeln!(out);
+	}
+	let _ = out.flush();
+}
+
+// Default reporter used by the CLI, keeps a single updating progress line on stdout
+pub struct StdoutReporter;
+
+impl ProgressReporter for StdoutReporter {
+	fn on_file_indexed(&self, done: usize, total: usize) {
+		write_progress(std::io::stdout().lock(), done, total);
+	}
+
+	fn on_file_failed(&self, path: &str, err: &str) {
+		eprintln!("\nFailed to index {}: {}", path, err);
+	}
+
+	fn on_file_skipped(&self, path: &str, reason: &SkipReason) {
+		eprintln!("Skipping {}: {}", path, reason);
+	}
+
+	fn on_embeddings_failed(&self, failed: usize, total: usize) {
+		eprintln!("{} of {} descriptions failed to embed and will not show up in searches", failed, total);
+	}
+}
+
+// Same progress line as StdoutReporter but on stderr, leaving stdout to machine-readable output. Everything else
+// already goes to stderr and is left to StdoutReporter.
+pub struct StderrReporter;
+
+impl ProgressReporter for StderrReporter {
+	fn on_file_indexed(&self, done: usize, total: usize) {
+		write_progress(std::io::stderr().lock(), done, total);
+	}
+
+	fn on_file_failed(&self, path: &str, err: &str) {
+		StdoutReporter.on_file_failed(path, err);
+	}
+
+	fn on_file_skipped(&self, path: &str, reason: &SkipReason) {
+		StdoutReporter.on_file_skipped(path, reason);
+	}
+
+	fn on_embeddings_failed(&self, failed: usize, total: usize) {
+		StdoutReporter.on_embeddings_failed(failed, total);
+	}
+}
+
+// Why a file was left out of the index without being sent to the LLM
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+	TooLarge(u64),
+	Binary,
+}
+
+impl std::fmt::Display for SkipReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SkipReason::TooLarge(bytes) => write!(f, "file is {} bytes, over the size limit", bytes),
+			SkipReason::Binary => write!(f, "file looks binary"),
+		}
+	}
+}
+
+impl std::error::Error for SkipReason {}
+
+// How much of a file is sniffed for null bytes, the same heuristic git uses to tell binary files apart
+const BINARY_SNIFF_BYTES: u64 = 8000;
+
+// Checks size and content of a file without reading all of it. Files that cannot even be opened pass, reading them
+// will report the actual error.
+pub fn should_skip(file: &str, config: &llm::LlmConfig) -> Option<SkipReason> {
+	use std::io::Read;
+	let size = std::fs::metadata(file).ok()?.len();
+	if size > config.max_file_bytes {
+		return Some(SkipReason::TooLarge(size));
+	}
+	let mut head = Vec::new();
+	std::fs::File::open(file).ok()?.take(BINARY_SNIFF_BYTES).read_to_end(&mut head).ok()?;
+	head.contains(&0).then_some(SkipReason::Binary)
+}
+
+// Canonicalizes a single file and parses the result
+pub fn index_file(file: &str, provider: &dyn llm::LlmProvider, config: &llm::LlmConfig) -> Result<llm::ParsedFile, llm::LlmError> {
+	if let Some(reason) = should_skip(file, config) {
+		return Err(reason.into());
+	}
+	let input = std::fs::read_to_string(file)?;
+	let res = llm::runtime().block_on(llm::request_llm_chunked(input.clone(), file, provider, config))?;
+	Ok(llm::parse_llm_response(res, file.to_string(), &input, provider)?)
+}
+
+// Indexes every file under `root` in a language we recognize with OpenAI, reporting progress on stdout. Fails only when `root`
+// cannot be read.
+pub fn index_directory(root: &str, config: &llm::LlmConfig) -> std::io::Result<code_base> {
+	let provider = llm::OpenAiProvider::new(config.clone());
+	index_directory_with_reporter(root, &provider, config, std::sync::Arc::new(StdoutReporter))
+}
+
+pub fn index_directory_with_reporter(root: &str, provider: &dyn llm::LlmProvider, config: &llm::LlmConfig, reporter: std::sync::Arc<dyn ProgressReporter>) -> std::io::Result<code_base> {
+	let files = list_files_filtered(root, &source_extensions(), DEFAULT_IGNORE_DIRS)?;
+	Ok(index_files(&files, provider, config, reporter))
+}
+
+// What indexing a set of files is expected to consume. Cached responses are not taken into account.
+#[derive(Debug, Clone, Default)]
+pub struct CostEstimate {
+	pub files: usize,
+	pub requests: usize,
+	pub input_tokens: usize,
+	// Assumes the canonical JSON is about as long as the source it describes, its descriptions are embedded too
+	pub output_tokens: usize,
+	// None when the model has no known price
+	pub usd: Option<f64>,
+}
+
+impl std::fmt::Display for CostEstimate {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{} files in {} requests, about {} input and {} output tokens",
+			self.files, self.requests, self.input_tokens, self.output_tokens
+		)?;
+		match self.usd {
+			Some(usd) => write!(f, ", roughly ${:.2}", usd),
+			None => write!(f, ", no price known for this model"),
+		}
+	}
+}
+
+// USD per million input and output tokens. Lookups match by prefix, so dated snapshots of a model share its price
+// and more specific names have to come first.
+const MODEL_PRICES: &[(&str, f64, f64)] = &[
+	("gpt-4.1-nano", 0.10, 0.40),
+	("gpt-4.1-mini", 0.40, 1.60),
+	("gpt-4.1", 2.00, 8.00),
+	("gpt-4o-mini", 0.15, 0.60),
+	("gpt-4o", 2.50, 10.00),
+	("claude-haiku-4-5", 1.00, 5.00),
+	("claude-sonnet-4", 3.00, 15.00),
+	("claude-opus-4", 15.00, 75.00),
+	("text-embedding-3-small", 0.02, 0.0),
+	("text-embedding-3-large", 0.13, 0.0),
+];
+
+fn model_price(model: &str) -> Option<(f64, f64)> {
+	MODEL_PRICES
+		.iter()
+		.find(|(name, _, _)| model.starts_with(name))
+		.map(|(_, input, output)| (*input, *output))
+}
+
+// Tokenizes every file the way index_file would send it, chunk by chunk with the base prompt in front of each
+// chunk. Files that cannot be read or fail the size and binary check are skipped, indexing would skip them as well.
+pub fn estimate_cost(files: &[String], config: &llm::LlmConfig) -> CostEstimate {
+	// Claude models have no public tokenizer, the OpenAI one is close enough for an estimate
+	let bpe = tiktoken_rs::bpe_for_model(&config.model).unwrap_or_else(|_| tiktoken_rs::o200k_base_singleton());
+	let prompt_tokens = bpe.encode_ordinary(&config.prompt).len();
+	let mut estimate = CostEstimate::default();
+	for file in files {
+		if should_skip(file, config).is_some() {
+			continue;
+		}
+		let Ok(source) = std::fs::read_to_string(file) else {
+			continue;
+		};
+		estimate.files += 1;
+		let chunks = llm::chunk_source(&source, config);
+		// Per-symbol files of several chunks take one more, small request for the file description
+		if config.chunk_mode == llm::ChunkMode::PerSymbol && chunks.len() > 1 {
+			estimate.requests += 1;
+		}
+		for chunk in chunks {
+			let chunk_tokens = bpe.encode_ordinary(&chunk).len();
+			estimate.requests += 1;
+			estimate.input_tokens += prompt_tokens + chunk_tokens;
+			estimate.output_tokens += chunk_tokens;
+		}
+	}
+	estimate.usd = model_price(&config.model).zip(model_price(&config.embedding_model)).map(|((input, output), (embedding, _))| {
+		(estimate.input_tokens as f64 * input + estimate.output_tokens as f64 * (output + embedding)) / 1_000_000.0
+	});
+	estimate
+}
+
+// Fans the files out over worker threads and assembles the results into a single code_base, with morphisms
+// linked to their owners and embeddings normalized for search. Every worker waits on one request at a time, so
+// the number of workers is what caps concurrent LLM requests, independent of the core count.
+pub fn index_files(files: &[String], provider: &dyn llm::LlmProvider, config: &llm::LlmConfig, reporter: std::sync::Arc<dyn ProgressReporter>) -> code_base {
+	index_files_with_checkpoint(files, provider, config, reporter, None)
+}
+
+// index_files, resuming from `checkpoint` and saving to it every `checkpoint.every` files. Files the checkpoint
+// already holds are not sent again, unless their file_hash changed. When checkpoint::interrupted is set, workers stop
+// picking up files, a last checkpoint is written and the partial code base is returned sorted and linked, but
+// without the embedding retry and normalization. A run that completes removes the checkpoint.
+pub fn index_files_with_checkpoint(
+	files: &[String],
+	provider: &dyn llm::LlmProvider,
+	config: &llm::LlmConfig,
+	reporter: std::sync::Arc<dyn ProgressReporter>,
+	checkpoint: Option<&checkpoint::Checkpoint>,
+) -> code_base {
+	let mut codebase = code_base::default();
+	// file_hash of every file in codebase, what the checkpoint needs to tell whether a file changed since
+	let mut hashes: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+	let _active = checkpoint.map(|_| checkpoint::ActiveRun::start());
+	let all_files = files;
+	// Binary and oversized files are dropped here, before any worker reads them in full
+	let files: Vec<String> = files
+		.iter()
+		.filter(|file| match should_skip(file, config) {
+			Some(reason) => {
+				reporter.on_file_skipped(file, &reason);
+				false
+			}
+			None => true,
+		})
+		.cloned()
+		.collect();
+	if let Some(checkpoint) = checkpoint {
+		match checkpoint.load() {
+			Ok(Some(checkpoint::Snapshot { codebase: mut resumed, hashes: resumed_hashes, .. })) => {
+				// Records of files outside this run, e.g. from another root or skipped by now, are dropped, and so
+				// are those of files that changed or were indexed with other settings
+				let wanted: std::collections::HashSet<&str> = files.iter().map(String::as_str).collect();
+				resumed.files.retain(|file| {
+					if !wanted.contains(file.path.as_str()) {
+						return false;
+					}
+					let unchanged = resumed_hashes.get(&file.path).is_some_and(|hash| checkpoint::file_hash(&file.path, config).as_ref() == Some(hash));
+					if !unchanged {
+						log::info!("Indexing {} again, it changed since the checkpoint", file.path);
+					}
+					unchanged
+				});
+				let kept: std::collections::HashSet<String> = resumed.files.iter().map(|file| file.path.clone()).collect();
+				resumed.objects.retain(|obj| kept.contains(&obj.file_path));
+				resumed.morphisms.retain(|morph| kept.contains(&morph.file_path));
+				log::info!("Resuming from {} with {} files already indexed", checkpoint.path.display(), resumed.files.len());
+				hashes = resumed_hashes.into_iter().filter(|(path, _)| kept.contains(path)).collect();
+				codebase.files = resumed.files;
+				codebase.objects = resumed.objects;
+				codebase.morphisms = resumed.morphisms;
+			}
+			Ok(None) => {}
+			Err(err) => log::warn!("Ignoring unreadable checkpoint {}: {}", checkpoint.path.display(), err),
+		}
+	}
+	let resumed: std::collections::HashSet<String> = codebase.files.iter().map(|file| file.path.clone()).collect();
+	let num_files = files.len();
+	let files: Vec<String> = files.into_iter().filter(|file| !resumed.contains(file)).collect();
+	// Progress counts the resumed files this run would have indexed, not every file the checkpoint held
+	let already_indexed = num_files - files.len();
+	let num_workers = config.max_concurrent_requests.max(1);
+	let chunks = chunk_files(&files, num_workers);
+	// Only checkpointed runs stop on Ctrl-C, the others cannot save their progress
+	let interrupted = || checkpoint.is_some() && checkpoint::interrupted();
+	let save = |codebase: &code_base, hashes: &std::collections::HashMap<String, String>| {
+		if let Some(checkpoint) = checkpoint
+			&& let Err(err) = checkpoint.save(codebase, hashes)
+		{
+			log::warn!("Failed to write checkpoint {}: {}", checkpoint.path.display(), err);
+		}
+	};
+	// Workers send every parsed file as soon as it is done and the collector merges it right away, so nothing is
+	// buffered per thread. The bound keeps workers from running far ahead of the collector.
+	let (sender, receiver) = std::sync::mpsc::sync_channel::<(usize, Option<String>, Result<llm::ParsedFile, llm::LlmError>)>(num_workers);
+	// Scoped threads can borrow the provider and config instead of requiring them to be 'static
+	std::thread::scope(|scope| {
+		let mut position = 0;
+		for file_chunk in chunks {
+			let sender = sender.clone();
+			let first = position;
+			position += file_chunk.len();
+			scope.spawn(move || {
+				for (offset, file) in file_chunk.iter().enumerate() {
+					if interrupted() {
+						return;
+					}
+					// Hashed before indexing, a file edited in between gets a stale hash and is indexed again on resume
+					let hash = checkpoint.and_then(|_| checkpoint::file_hash(file, config));
+					if sender.send((first + offset, hash, index_file(file, provider, config))).is_err() {
+						return;
+					}
+				}
+			});
+		}
+		// The collector stops once every worker has dropped its sender
+		drop(sender);
+		for (done, (position, hash, parsed)) in receiver.iter().enumerate() {
+			match parsed {
+				Ok((file, objects, morphisms)) => {
+					if let Some(hash) = hash {
+						hashes.insert(file.path.clone(), hash);
+					}
+					codebase.files.push(file);
+					codebase.objects.extend(objects);
+					codebase.morphisms.extend(morphisms);
+				}
+				Err(err) => {
+					reporter.on_file_failed(&files[position], &err.to_string());
+					codebase.failures.push((files[position].clone(), err.to_string()));
+				}
+			}
+			let done = done + 1;
+			reporter.on_file_indexed(already_indexed + done, num_files);
+			if checkpoint.is_some_and(|checkpoint| checkpoint.every > 0 && done % checkpoint.every == 0) {
+				save(&codebase, &hashes);
+			}
+		}
+	});
+	// Files arrive in completion order, put everything back in input order so an index is reproducible. The sort
+	// is stable, so records of one file keep their declaration order.
+	let order: std::collections::HashMap<&str, usize> = all_files.iter().enumerate().map(|(i, file)| (file.as_str(), i)).collect();
+	codebase.files.sort_by_key(|file| order.get(file.path.as_str()).copied());
+	codebase.objects.sort_by_key(|obj| order.get(obj.file_path.as_str()).copied());
+	codebase.morphisms.sort_by_key(|morph| order.get(morph.file_path.as_str()).copied());
+	codebase.failures.sort_by_key(|(path, _)| order.get(path.as_str()).copied());
+	codebase.link_morphisms();
+	if interrupted() {
+		save(&codebase, &hashes);
+		return codebase;
+	}
+	if let Some(checkpoint) = checkpoint
+		&& let Err(err) = checkpoint.remove()
+	{
+		log::warn!("Failed to remove checkpoint {}: {}", checkpoint.path.display(), err);
+	}
+	if codebase.retry_failed_embeddings(provider) > 0 {
+		let (failed, total) = codebase.embedding_failures();
+		reporter.on_embeddings_failed(failed, total);
+	}
+	// Stored embeddings never change from here on, normalize once so every search is just dot products
+	codebase.normalize_embeddings();
+	log::info!("Indexed {} files: {} objects, {} morphisms, {} failed", codebase.files.len(), codebase.objects.len(), codebase.morphisms.len(), codebase.failures.len());
+	codebase
+}
+
+// Directories that never contain source worth canonicalizing
+pub const DEFAULT_IGNORE_DIRS: &[&str] = &[".git", "target", "node_modules", crate::cache::DEFAULT_CACHE_DIR];
+
+// Source extensions we know how to index and the language each one maps to
+pub const LANGUAGE_EXTENSIONS: &[(&str, &str)] = &[
+	("rs", "Rust"),
+	("py", "Python"),
+	("pyi", "Python"),
+	("js", "JavaScript"),
+	("mjs", "JavaScript"),
+	("cjs", "JavaScript"),
+	("jsx", "JavaScript"),
+	("ts", "TypeScript"),
+	("tsx", "TypeScript"),
+	("go", "Go"),
+	("java", "Java"),
+	("kt", "Kotlin"),
+	("scala", "Scala"),
+	("c", "C"),
+	("h", "C"),
+	("cc", "C++"),
+	("cpp", "C++"),
+	("cxx", "C++"),
+	("hpp", "C++"),
+	("cs", "C#"),
+	("rb", "Ruby"),
+	("php", "PHP"),
+	("swift", "Swift"),
+	("m", "Objective-C"),
+	("lua", "Lua"),
+	("sh", "Shell"),
+	("hs", "Haskell"),
+	("ml", "OCaml"),
+	("ex", "Elixir"),
+	("exs", "Elixir"),
+	("erl", "Erlang"),
+	("clj", "Clojure"),
+	("dart", "Dart"),
+	("zig", "Zig"),
+];
+
+// Maps a path to its language by extension, None for extensions that are not in LANGUAGE_EXTENSIONS
+pub fn detect_language(path: &str) -> Option<String> {
+	let ext = std::path::Path::new(path).extension()?.to_str()?;
+	LANGUAGE_EXTENSIONS
+		.iter()
+		.find(|(known, _)| known.eq_ignore_ascii_case(ext))
+		.map(|(_, language)| language.to_string())
+}
+
+// Every extension detect_language recognizes, suitable for list_files_filtered
+pub fn source_extensions() -> Vec<&'static str> {
+	LANGUAGE_EXTENSIONS.iter().map(|(ext, _)| *ext).collect()
 }
 
 // A function that will loop through all the files in a root directory
-pub fn list_files(root_dir : &str) -> Vec<String> {
-	let paths = std::fs::read_dir(root_dir).unwrap();
+pub fn list_files(root_dir : &str) -> std::io::Result<Vec<String>> {
+	list_files_filtered(root_dir, &[], &[])
+}
+
+// Same walk as list_files, but only keeps files whose extension is in `extensions` and never descends
+// into a directory whose name is in `ignore_dirs`. An empty `extensions` slice keeps every file.
+// Only an unreadable root is an error, entries below it that cannot be read are skipped with a warning.
+pub fn list_files_filtered(root_dir : &str, extensions: &[&str], ignore_dirs: &[&str]) -> std::io::Result<Vec<String>> {
+	// Fail early when the root itself cannot be walked
+	std::fs::read_dir(root_dir)?;
     // Create a stack vector to hold directories to visit
-    let mut stack : Vec<String> = Vec::new();
+    let mut stack : Vec<std::path::PathBuf> = Vec::new();
 	let mut files : Vec<String> = Vec::new();
+	// Canonical paths of the directories already walked, so symlink loops are entered only once
+	let mut visited: std::collections::HashSet<std::path::PathBuf> = std::collections::HashSet::new();
 	
-    stack.push(root_dir.to_owned());
-	while stack.len() > 0 {
-		let current_dir = stack.pop().unwrap();
-		let paths = std::fs::read_dir(current_dir).unwrap();
+    stack.push(std::path::PathBuf::from(root_dir));
+	while let Some(current_dir) = stack.pop() {
+		match std::fs::canonicalize(&current_dir) {
+			Ok(canonical) => {
+				if !visited.insert(canonical) {
+					continue;
+				}
+			}
+			Err(err) => {
+				log::warn!("Skipping {}: {}", current_dir.display(), err);
+				continue;
+			}
+		}
+		let paths = match std::fs::read_dir(&current_dir) {
+			Ok(paths) => paths,
+			Err(err) => {
+				log::warn!("Skipping {}: {}", current_dir.display(), err);
+				continue;
+			}
+		};
 	
+		// Entries come in file system order, sort them so repeated runs list files the same way
+		let mut entries: Vec<std::path::PathBuf> = Vec::new();
 		for path in paths {
-			let path = path.unwrap().path();
-			if path.is_file() {
+			match path {
+				Ok(entry) => entries.push(entry.path()),
+				Err(err) => log::warn!("Skipping entry in {}: {}", current_dir.display(), err),
+			}
+		}
+		entries.sort();
+		let mut subdirs: Vec<std::path::PathBuf> = Vec::new();
+		for path in entries {
+			if path.is_file() && has_extension(&path, extensions) {
 				// Process file
-				let path_str = path.to_str().unwrap().to_owned();
-				files.push(path_str);
+				match path.to_str() {
+					Some(path_str) => files.push(path_str.to_owned()),
+					None => log::warn!("Skipping {}: path is not valid UTF-8", path.display()),
+				}
 			}
-			if path.is_dir() {
-				let path_str = path.to_str().unwrap().to_owned();
-				stack.push(path_str);
+			if path.is_dir() && !is_ignored_dir(&path, ignore_dirs) {
+				subdirs.push(path);
 			}		
 		}
+		// Reversed so the stack pops subdirectories in sorted order
+		stack.extend(subdirs.into_iter().rev());
+	}
+	files.sort();
+	Ok(files)
+}
+
+// Walks the tree like list_files but honors .gitignore files found at every directory level,
+// including nested ones and negated patterns such as `!keep.rs`. The .git directory itself is skipped.
+pub fn list_files_gitignore(root_dir: &str) -> std::io::Result<Vec<String>> {
+	list_files_gitignore_filtered(root_dir, &[], &[])
+}
+
+// list_files_gitignore with the extension and directory filters of list_files_filtered on top
+pub fn list_files_gitignore_filtered(root_dir: &str, extensions: &[&str], ignore_dirs: &[&str]) -> std::io::Result<Vec<String>> {
+	std::fs::read_dir(root_dir)?;
+	let ignore_dirs: Vec<String> = ignore_dirs.iter().map(|dir| dir.to_string()).collect();
+	// Symlinks are not followed, so the walk cannot loop
+	let walker = ignore::WalkBuilder::new(root_dir)
+		.hidden(false)
+		.require_git(false)
+		.filter_entry(move |entry| {
+			let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+			entry.file_name() != ".git" && !(is_dir && entry.depth() > 0 && is_ignored_dir(entry.path(), &ignore_dirs))
+		})
+		.build();
+	let mut files : Vec<String> = Vec::new();
+	for entry in walker {
+		let entry = match entry {
+			Ok(entry) => entry,
+			Err(err) => {
+				log::warn!("Skipping entry: {}", err);
+				continue;
+			}
+		};
+		if entry.file_type().is_some_and(|t| t.is_file()) && has_extension(entry.path(), extensions) {
+			match entry.path().to_str() {
+				Some(path_str) => files.push(path_str.to_owned()),
+				None => log::warn!("Skipping {}: path is not valid UTF-8", entry.path().display()),
+			}
+		}
+	}
+	files.sort();
+	Ok(files)
+}
+
+pub(crate) fn has_extension(path: &std::path::Path, extensions: &[&str]) -> bool {
+	if extensions.is_empty() {
+		return true;
+	}
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some(ext) => extensions.iter().any(|wanted| wanted.trim_start_matches('.').eq_ignore_ascii_case(ext)),
+		None => false,
 	}
-	return files;
 }
 
+fn is_ignored_dir(path: &std::path::Path, ignore_dirs: &[impl AsRef<str>]) -> bool {
+	match path.file_name().and_then(|name| name.to_str()) {
+		Some(name) => ignore_dirs.iter().any(|dir| dir.as_ref() == name),
+		None => false,
+	}
+}
 
-pub fn cosine_similarity(vec1: &Vec<f32>, vec2: &Vec<f32>) -> f32 {
-	let dot_product: f32 = vec1.iter().zip(vec2.iter()).map(|(a, b)| a * b).sum();
-	let magnitude1: f32 = vec1.iter().map(|x| x * x).sum::<f32>().sqrt();
-	let magnitude2: f32 = vec2.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+// Splits the files into at most `num_chunks` contiguous chunks whose sizes differ by at most one, so every file
+// lands in exactly one chunk. Fewer files than chunks simply yields one chunk per file, never an empty chunk.
+pub fn chunk_files(files: &[String], num_chunks: usize) -> Vec<Vec<String>> {
+	if files.is_empty() {
+		return Vec::new();
+	}
+	let num_chunks = num_chunks.clamp(1, files.len());
+	let base = files.len() / num_chunks;
+	let remainder = files.len() % num_chunks;
+	let mut chunks = Vec::with_capacity(num_chunks);
+	let mut start = 0;
+	for i in 0..num_chunks {
+		// The first `remainder` chunks take one extra file
+		let len = base + if i < remainder { 1 } else { 0 };
+		chunks.push(files[start..start + len].to_vec());
+		start += len;
+	}
+	chunks
+}
+
+// Returns None when the vectors cannot be compared: either is empty (a failed embedding), their dimensions
+// differ, or one has zero magnitude
+pub fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> Option<f32> {
+	if !comparable(vec1, vec2) {
+		return None;
+	}
+	let magnitude1: f32 = dot_product(vec1, vec1).sqrt();
+	let magnitude2: f32 = dot_product(vec2, vec2).sqrt();
 	if magnitude1 == 0.0 || magnitude2 == 0.0 {
-		return 0.0;
+		return None;
+	}
+	Some(dot_product(vec1, vec2) / (magnitude1 * magnitude2))
+}
+
+fn comparable(vec1: &[f32], vec2: &[f32]) -> bool {
+	!vec1.is_empty() && vec1.len() == vec2.len()
+}
+// Keeps only the k best hits of an already sorted list, a top_k of 0 keeps everything
+fn truncate_top_k<T>(hits: &mut Vec<T>, top_k: usize) {
+	if top_k > 0 {
+		hits.truncate(top_k);
+	}
+}
+
+// Hits [offset, offset + top_k) of an already sorted list, e.g. the next page behind a "show more" button. An offset
+// past the end gives an empty page, a top_k of 0 keeps everything after the offset.
+pub fn page<T>(hits: Vec<T>, offset: usize, top_k: usize) -> Vec<T> {
+	let mut page: Vec<T> = hits.into_iter().skip(offset).collect();
+	truncate_top_k(&mut page, top_k);
+	page
+}
+
+// Scales a vector to unit length in place, zero vectors are left untouched
+pub fn normalize(vec: &mut [f32]) {
+	let magnitude: f32 = dot_product(vec, vec).sqrt();
+	if magnitude == 0.0 {
+		return;
+	}
+	for x in vec.iter_mut() {
+		*x /= magnitude;
+	}
+}
+
+// Width of the SIMD registers used by dot_product, every embedding model we use has a multiple of it as dimension
+const SIMD_LANES: usize = 8;
+
+// Vectors of equal length that split evenly into SIMD lanes take the vectorized path, anything else the scalar loop
+pub fn dot_product(vec1: &[f32], vec2: &[f32]) -> f32 {
+	if vec1.len() == vec2.len() && vec1.len().is_multiple_of(SIMD_LANES) {
+		dot_product_simd(vec1, vec2)
+	} else {
+		dot_product_scalar(vec1, vec2)
+	}
+}
+
+pub fn dot_product_scalar(vec1: &[f32], vec2: &[f32]) -> f32 {
+	vec1.iter().zip(vec2.iter()).map(|(a, b)| a * b).sum()
+}
+
+fn dot_product_simd(vec1: &[f32], vec2: &[f32]) -> f32 {
+	let lanes = |chunk: &[f32]| wide::f32x8::from(<[f32; SIMD_LANES]>::try_from(chunk).unwrap());
+	let mut sum = wide::f32x8::ZERO;
+	for (a, b) in vec1.chunks_exact(SIMD_LANES).zip(vec2.chunks_exact(SIMD_LANES)) {
+		sum = lanes(a).mul_add(lanes(b), sum);
+	}
+	sum.reduce_add()
+}
+
+// Cosine similarity against a stored embedding, skipping the norm computations when the embeddings are normalized.
+// The query is expected to be normalized already in that case. None means the item is not a match at all.
+fn similarity(normalized: bool, query_vec: &[f32], embedding: &[f32]) -> Option<f32> {
+	if normalized {
+		if !comparable(query_vec, embedding) {
+			return None;
+		}
+		Some(dot_product(query_vec, embedding))
+	} else {
+		cosine_similarity(query_vec, embedding)
+	}
+}
+
+// Lexical side of hybrid search: the query as a whole plus its lowercase identifier-like terms
+pub struct KeywordQuery {
+	phrase: String,
+	terms: Vec<String>,
+	// Share of the final score given to the lexical match, the rest comes from cosine similarity
+	pub weight: f32,
+}
+
+impl KeywordQuery {
+	pub fn new(query: &str, weight: f32) -> Self {
+		let terms = query
+			.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+			.filter(|term| !term.is_empty())
+			.map(|term| term.to_lowercase())
+			.collect();
+		KeywordQuery {
+			phrase: query.trim().to_lowercase(),
+			terms,
+			weight: weight.clamp(0.0, 1.0),
+		}
+	}
+
+	// True when the query is literally the item name, e.g. searching `parse_llm_response`
+	fn is_exact_name(&self, name: &str) -> bool {
+		!self.phrase.is_empty() && name.to_lowercase() == self.phrase
+	}
+
+	// Score in [0, 1]. An exact name is a perfect match, otherwise terms found in the name count fully and
+	// terms only found in the description count half.
+	fn score(&self, name: &str, description: &str) -> f32 {
+		if self.is_exact_name(name) {
+			return 1.0;
+		}
+		if self.terms.is_empty() {
+			return 0.0;
+		}
+		let name = name.to_lowercase();
+		let description = description.to_lowercase();
+		let matched: f32 = self.terms
+			.iter()
+			.map(|term| {
+				if name.contains(term.as_str()) {
+					1.0
+				} else if description.contains(term.as_str()) {
+					0.5
+				} else {
+					0.0
+				}
+			})
+			.sum();
+		matched / self.terms.len() as f32
+	}
+}
+
+// Final score of an item and whether its name is exactly the query. Without keywords the score is pure cosine.
+fn blend(keywords: Option<&KeywordQuery>, semantic: f32, name: &str, description: &str) -> (f32, bool) {
+	match keywords {
+		Some(keywords) if keywords.weight > 0.0 => {
+			let lexical = keywords.score(name, description);
+			((1.0 - keywords.weight) * semantic + keywords.weight * lexical, keywords.is_exact_name(name))
+		}
+		_ => (semantic, false),
 	}
-	dot_product / (magnitude1 * magnitude2)
 }
+
 // A function given some query, with a query type will search the code base for relevant information
-pub fn search_codebase(code_base: &code_base, query: String, query_type: &str) -> query_result {
-	// For now we will return an empty query result
+// query_type is one of "file", "object", "morphism" or "all", the last filling every category from one embedding.
+// Hits scoring below min_score are dropped before top_k is applied. Every category is ranked on its own, search
+// ranks all kinds in one list and takes typed options instead.
+pub fn search_codebase(code_base: &code_base, query: String, query_type: &str, top_k: usize, min_score: f32, provider: &dyn llm::LlmProvider) -> query_result {
+	search_codebase_hybrid(code_base, query, "", query_type, top_k, min_score, 0.0, provider)
+}
+
+// Same as search_codebase, but blends cosine similarity with a keyword match over names and descriptions.
+// `embedded` is the text sent to the embedder and `keyword` the one matched lexically, they differ once the query
+// was expanded since the rewrite would match far too much. keyword_weight is the lexical share of the score (0.0 is
+// pure semantic search), and items named exactly like `keyword` always rank first.
+#[allow(clippy::too_many_arguments)]
+pub fn search_codebase_hybrid(code_base: &code_base, embedded: String, keyword: &str, query_type: &str, top_k: usize, min_score: f32, keyword_weight: f32, provider: &dyn llm::LlmProvider) -> query_result {
+	llm::runtime().block_on(search_codebase_hybrid_async(code_base, embedded, keyword, query_type, top_k, min_score, keyword_weight, provider))
+}
+
+// Async form of search_codebase for callers already running on a Tokio runtime, e.g. an interactive session issuing
+// many queries. The blocking versions wrap these and must not be called from inside a runtime.
+pub async fn search_codebase_async(code_base: &code_base, query: String, query_type: &str, top_k: usize, min_score: f32, provider: &dyn llm::LlmProvider) -> query_result {
+	search_codebase_hybrid_async(code_base, query, "", query_type, top_k, min_score, 0.0, provider).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn search_codebase_hybrid_async(code_base: &code_base, embedded: String, keyword: &str, query_type: &str, top_k: usize, min_score: f32, keyword_weight: f32, provider: &dyn llm::LlmProvider) -> query_result {
 	// A quick vector search based on embeddings 
-	let query_vec:Result<Vec<f32>, Box<dyn std::error::Error>> = tokio::runtime::Runtime::new().unwrap().block_on(llm::get_embeddings(query.clone()));
-	if query_vec.is_err() {
-		return query_result {
-			relevant_files: Vec::new(),
-			relevant_objects: Vec::new(),
-			relevant_morphisms: Vec::new(),
+	let Some(query_vec) = embed_query(code_base, &embedded, provider).await else {
+		return query_result::default();
+	};
+	let keywords = KeywordQuery::new(keyword, keyword_weight);
+	let keywords = Some(&keywords).filter(|k| k.weight > 0.0);
+	let rank_query = RankQuery {
+		query_vec: &query_vec,
+		normalized: code_base.normalized,
+		top_k,
+		offset: 0,
+		min_score,
+		keywords,
+	};
+	let ann = code_base.ann_index.as_ref();
+	//Now that we have the vector we can search the code base
+	let mut result = query_result::default();
+	if query_type == "file" || query_type == "all" {
+		result.relevant_files = rank_with(&code_base.files, ann.map(|ann| &ann.files), &rank_query);
+	}
+	if query_type == "object" || query_type == "all" {
+		result.relevant_objects = rank_with(&code_base.objects, ann.map(|ann| &ann.objects), &rank_query);
+	}
+	if query_type == "morphism" || query_type == "all" {
+		result.relevant_morphisms = rank_with(&code_base.morphisms, ann.map(|ann| &ann.morphisms), &rank_query);
+	}
+	result
+}
+
+// Embeds the query, normalized when the code base is. Failures are reported and yield no results.
+async fn embed_query(code_base: &code_base, query: &str, provider: &dyn llm::LlmProvider) -> Option<Vec<f32>> {
+	let mut query_vec = match provider.embed(query.to_string()).await {
+		Ok(query_vec) => query_vec,
+		Err(err) => {
+			log::warn!("Failed to embed query '{}': {}", query, err);
+			return None;
+		}
+	};
+	if code_base.normalized {
+		normalize(&mut query_vec);
+	}
+	Some(query_vec)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemType {
+	File,
+	Object,
+	Morphism,
+}
+
+pub const ALL_ITEM_TYPES: [ItemType; 3] = [ItemType::File, ItemType::Object, ItemType::Morphism];
+
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+	// Kinds of item to search, hits of all of them are ranked against each other
+	pub types: Vec<ItemType>,
+	// Number of hits returned overall, 0 returns everything
+	pub top_k: usize,
+	// Ranked hits skipped before top_k is applied, so the same query can be paged through
+	pub offset: usize,
+	// Hits scoring below this are dropped. Cosine scores can be negative, the default of f32::NEG_INFINITY keeps
+	// every hit and 0.0 drops the ones pointing away from the query.
+	pub min_score: f32,
+	// Lexical share of the score as in search_codebase_hybrid, 0.0 is pure semantic search
+	pub keyword_weight: f32,
+	// Rewrite the query with the LLM before embedding it, see llm::expand_query. Keywords still match the query as typed.
+	pub expand: bool,
+	// Only items from files in this language, e.g. "Rust". Objects and morphisms take the language of their file.
+	pub language: Option<String>,
+	// Let the LLM reorder the best RERANK_CANDIDATES hits before the page is cut, costs one extra request
+	pub rerank: bool,
+	// Only objects with this obj_type and morphisms with this morph_type, e.g. "struct". Files have no type and are
+	// left out while it is set.
+	pub obj_type: Option<String>,
+}
+
+impl Default for SearchOptions {
+	fn default() -> Self {
+		SearchOptions {
+			types: ALL_ITEM_TYPES.to_vec(),
+			top_k: 10,
+			offset: 0,
+			min_score: f32::NEG_INFINITY,
+			keyword_weight: 0.0,
+			expand: false,
+			rerank: false,
+			language: None,
+			obj_type: None,
+		}
+	}
+}
+
+// The language and type filters of SearchOptions, checked before an item is scored. Both compare case-insensitively
+// since the model is not consistent about "rust" versus "Rust".
+struct Filters<'a> {
+	language: Option<&'a str>,
+	obj_type: Option<&'a str>,
+	// Language of every indexed file, objects and morphisms are matched through their file_path
+	file_languages: std::collections::HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Filters<'a> {
+	fn new(code_base: &'a code_base, options: &'a SearchOptions) -> Self {
+		let language = options.language.as_deref();
+		let file_languages = match language {
+			Some(_) => code_base.files.iter().map(|file| (file.path.as_str(), file.language.as_str())).collect(),
+			None => std::collections::HashMap::new(),
 		};
+		Filters { language, obj_type: options.obj_type.as_deref(), file_languages }
 	}
-	let query_vec = query_vec.unwrap();
-	//Now that we have the vector we can search the code base
-	if query_type == "file" {
-		let mut relevant_files = Vec::new();
-		let mut file_similarities = Vec::new();
-		for file in code_base.files.iter() {
-			// Compute similarity between query and file embedding as a simple heuristic
-			let file_vec = file.embedding.clone();
-			let similarity = cosine_similarity(&query_vec, &file_vec);
-			relevant_files.push((*file).clone());
-			file_similarities.push(similarity);
-			println!("Similarity between query and file {}: {}", file.path, similarity);
-		}
-		//Sort relevant files by similarity descending by similarity
-		let mut combined: Vec<(llm::File, f32)> = relevant_files.into_iter().zip(file_similarities.into_iter()).collect();
-		combined.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-		let relevant_files: Vec<llm::File> = combined.into_iter().map(|(file, _)| file).collect();
-		return query_result {
-			relevant_files,
-			relevant_objects: Vec::new(),
-			relevant_morphisms: Vec::new(),
-		};
-	} else if query_type == "object" {
-		// Search for objects
-		let mut relevant_objects = Vec::new();
-		let mut object_similarities = Vec::new();
-		for obj in code_base.objects.iter() {
-			let obj_vec = obj.embedding.clone();
-			let similarity = cosine_similarity(&query_vec, &obj_vec);
-			object_similarities.push(similarity);
-			relevant_objects.push(obj.clone());
-		}
-		// Sort relevant objects by similarity descending
-		let mut combined: Vec<(llm::Object, f32)> = relevant_objects.into_iter().zip(object_similarities.into_iter()).collect();
-		combined.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-		let relevant_objects: Vec<llm::Object> = combined.into_iter().map(|(obj, _)| obj).collect();
-		return query_result {
-			relevant_files: Vec::new(),
-			relevant_objects,
-			relevant_morphisms: Vec::new(),
-		};
-	}
-	 else if query_type == "morphism" {
-		// Search for morphisms
-		let mut relevant_morphisms = Vec::new();
-		let mut morphism_similarities = Vec::new();
-		for morph in code_base.morphisms.iter() {
-			let morph_vec = morph.embedding.clone();
-			let similarity = cosine_similarity(&query_vec, &morph_vec);
-			morphism_similarities.push(similarity);
-			relevant_morphisms.push(morph.clone());
-		}
-		// Sort relevant morphisms by similarity descending
-		let mut combined: Vec<(llm::Morphism, f32)> = relevant_morphisms.into_iter().zip(morphism_similarities.into_iter()).collect();
-		combined.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-		let relevant_morphisms: Vec<llm::Morphism> = combined.into_iter().map(|(morph, _)| morph).collect();
-		return query_result {
-			relevant_files: Vec::new(),
-			relevant_objects: Vec::new(),
-			relevant_morphisms,
-		};
-	}
-	return query_result {
-		relevant_files: Vec::new(),
-		relevant_objects: Vec::new(),
-		relevant_morphisms: Vec::new(),
+
+	// Files the model gave no language fall back to the extension table
+	fn keeps_language(&self, path: &str) -> bool {
+		let Some(wanted) = self.language else {
+			return true;
+		};
+		match self.file_languages.get(path).filter(|language| !language.is_empty()) {
+			Some(language) => language.eq_ignore_ascii_case(wanted),
+			None => detect_language(path).is_some_and(|language| language.eq_ignore_ascii_case(wanted)),
+		}
+	}
+
+	fn keeps_type(&self, item_type: &str) -> bool {
+		self.obj_type.is_none_or(|wanted| item_type.eq_ignore_ascii_case(wanted))
+	}
+
+	fn keeps_file(&self, file: &llm::File) -> bool {
+		self.obj_type.is_none() && self.keeps_language(&file.path)
+	}
+
+	fn keeps_object(&self, obj: &llm::Object) -> bool {
+		self.keeps_type(&obj.obj_type) && self.keeps_language(&obj.file_path)
+	}
+
+	fn keeps_morphism(&self, morph: &llm::Morphism) -> bool {
+		self.keeps_type(&morph.morph_type) && self.keeps_language(&morph.file_path)
+	}
+}
+
+#[derive(Debug, Clone)]
+pub enum Hit {
+	File(llm::File),
+	Object(llm::Object),
+	Morphism(llm::Morphism),
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+	pub hit: Hit,
+	pub score: f32,
+}
+
+// One ranked list mixing every requested kind of item, best first, e.g. for a "go to anything" box
+pub fn search(code_base: &code_base, query: &str, options: &SearchOptions, provider: &dyn llm::LlmProvider) -> Vec<SearchHit> {
+	llm::runtime().block_on(search_async(code_base, query, options, provider))
+}
+
+pub async fn search_async(code_base: &code_base, query: &str, options: &SearchOptions, provider: &dyn llm::LlmProvider) -> Vec<SearchHit> {
+	let embedded = if options.expand { llm::expand_query(query, provider).await } else { query.to_string() };
+	let Some(query_vec) = embed_query(code_base, &embedded, provider).await else {
+		return Vec::new();
+	};
+	let keywords = KeywordQuery::new(query, options.keyword_weight);
+	let rank_query = RankQuery {
+		query_vec: &query_vec,
+		normalized: code_base.normalized,
+		top_k: options.top_k,
+		offset: options.offset,
+		min_score: options.min_score,
+		keywords: Some(&keywords).filter(|k| k.weight > 0.0),
+	};
+	let filters = Filters::new(code_base, options);
+	let ann = code_base.ann_index.as_ref();
+	let mut scored: Vec<(SearchHit, f32, bool)> = Vec::new();
+	for item_type in ALL_ITEM_TYPES.into_iter().filter(|item_type| options.types.contains(item_type)) {
+		let hits: Vec<(Hit, f32, bool)> = match item_type {
+			ItemType::File => score_items(&code_base.files, ann.map(|ann| &ann.files), &rank_query, |file| filters.keeps_file(file))
+				.into_iter()
+				.map(|(file, score, exact)| (Hit::File(file), score, exact))
+				.collect(),
+			ItemType::Object => score_items(&code_base.objects, ann.map(|ann| &ann.objects), &rank_query, |obj| filters.keeps_object(obj))
+				.into_iter()
+				.map(|(obj, score, exact)| (Hit::Object(obj), score, exact))
+				.collect(),
+			ItemType::Morphism => score_items(&code_base.morphisms, ann.map(|ann| &ann.morphisms), &rank_query, |morph| filters.keeps_morphism(morph))
+				.into_iter()
+				.map(|(morph, score, exact)| (Hit::Morphism(morph), score, exact))
+				.collect(),
+		};
+		scored.extend(hits.into_iter().map(|(hit, score, exact)| (SearchHit { hit, score }, score, exact)));
+	}
+	// Reranking sees the best hits overall, the page is cut from its order
+	let ranked = if options.rerank {
+		page(rerank(query, finish_ranking(scored, 0, 0), RERANK_CANDIDATES, provider).await, options.offset, options.top_k)
+	} else {
+		finish_ranking(scored, options.offset, options.top_k)
+	};
+	ranked.into_iter().map(|(hit, _)| hit).collect()
+}
+
+// How many of the best hits rerank hands to the LLM, enough to fix the order of a first page at a bounded cost
+pub const RERANK_CANDIDATES: usize = 20;
+
+// Second stage of a search: the LLM reorders the first `top_n` hits by relevance to the query, the rest keep their
+// place behind them. Scores stay the cosine or blended ones, so they are no longer sorted afterwards. When the
+// request fails the hits are returned as they came.
+pub async fn rerank<T: Embeddable>(query: &str, mut hits: Vec<(T, f32)>, top_n: usize, provider: &dyn llm::LlmProvider) -> Vec<(T, f32)> {
+	let n = top_n.min(hits.len());
+	if n < 2 {
+		return hits;
+	}
+	let candidates: Vec<String> = hits[..n].iter().map(|(item, _)| format!("{}: {}", item.name(), item.description())).collect();
+	let order = match llm::rerank_order(query, &candidates, provider).await {
+		Ok(order) => order,
+		Err(err) => {
+			log::warn!("Failed to rerank results for '{}', keeping the similarity order: {}", query, err);
+			return hits;
+		}
+	};
+	let rest = hits.split_off(n);
+	let mut head: Vec<Option<(T, f32)>> = hits.into_iter().map(Some).collect();
+	let mut reranked: Vec<(T, f32)> = order.into_iter().filter_map(|i| head[i].take()).collect();
+	reranked.extend(rest);
+	reranked
+}
+
+// Searches files, objects and morphisms at once, embedding the query a single time
+pub fn search_all(code_base: &code_base, query: String, top_k: usize, min_score: f32, provider: &dyn llm::LlmProvider) -> query_result {
+	search_codebase(code_base, query, "all", top_k, min_score, provider)
+}
+
+// Default for code_base::dedup, high enough that only copies of the same definition are merged
+pub const DEFAULT_DEDUP_THRESHOLD: f32 = 0.95;
+
+// Keeps records in order, folding every later record into an earlier one with the same key and a similar embedding
+fn dedup_records<T>(records: Vec<T>, threshold: f32, key: impl Fn(&T) -> (String, &[f32]), absorb: impl Fn(&mut T, T)) -> Vec<T> {
+	let mut kept: Vec<T> = Vec::with_capacity(records.len());
+	let mut by_key: std::collections::HashMap<String, Vec<usize>> = std::collections::HashMap::new();
+	for record in records {
+		let (name, embedding) = key(&record);
+		let existing = by_key.get(&name).and_then(|candidates| {
+			candidates.iter().copied().find(|&i| {
+				cosine_similarity(key(&kept[i]).1, embedding).is_some_and(|similarity| similarity >= threshold)
+			})
+		});
+		match existing {
+			Some(i) => absorb(&mut kept[i], record),
+			None => {
+				by_key.entry(name).or_default().push(kept.len());
+				kept.push(record);
+			}
+		}
+	}
+	kept
+}
+
+fn absorb_duplicate(paths: &mut Vec<String>, own_path: &str, path: String, more: Vec<String>) {
+	for path in std::iter::once(path).chain(more) {
+		if path != own_path && !paths.contains(&path) {
+			paths.push(path);
+		}
+	}
+}
+
+// Positions to score: the ANN candidates when an index was built, otherwise every item for an exact search
+fn candidate_positions(index: Option<&ann::IvfIndex>, len: usize, query_vec: &[f32]) -> Vec<usize> {
+	match index {
+		Some(index) => index.candidates(query_vec),
+		None => (0..len).collect(),
+	}
+}
+
+// Sorts scored items best first, exact name matches ahead of everything else, then cuts out the requested page
+pub(crate) fn finish_ranking<T>(mut scored: Vec<(T, f32, bool)>, offset: usize, top_k: usize) -> Vec<(T, f32)> {
+	scored.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal)));
+	page(scored.into_iter().map(|(item, score, _)| (item, score)).collect(), offset, top_k)
+}
+
+// Anything stored with an embedding that search can rank. Name and description feed the keyword side of hybrid search.
+pub trait Embeddable {
+	fn embedding(&self) -> &[f32];
+	fn name(&self) -> &str;
+	fn description(&self) -> &str;
+}
+
+impl Embeddable for llm::File {
+	fn embedding(&self) -> &[f32] {
+		&self.embedding
+	}
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn description(&self) -> &str {
+		&self.description
+	}
+}
+
+impl Embeddable for llm::Object {
+	fn embedding(&self) -> &[f32] {
+		&self.embedding
+	}
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn description(&self) -> &str {
+		&self.description
+	}
+}
+
+impl Embeddable for SearchHit {
+	fn embedding(&self) -> &[f32] {
+		match &self.hit {
+			Hit::File(file) => file.embedding(),
+			Hit::Object(obj) => obj.embedding(),
+			Hit::Morphism(morph) => morph.embedding(),
+		}
+	}
+
+	// Files are known by their path, the bare file name is ambiguous across directories
+	fn name(&self) -> &str {
+		match &self.hit {
+			Hit::File(file) => &file.path,
+			Hit::Object(obj) => obj.name(),
+			Hit::Morphism(morph) => morph.name(),
+		}
+	}
+
+	fn description(&self) -> &str {
+		match &self.hit {
+			Hit::File(file) => file.description(),
+			Hit::Object(obj) => obj.description(),
+			Hit::Morphism(morph) => morph.description(),
+		}
+	}
+}
+
+impl Embeddable for llm::Morphism {
+	fn embedding(&self) -> &[f32] {
+		&self.embedding
+	}
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn description(&self) -> &str {
+		&self.description
+	}
+}
+
+// Ranks any slice of embeddable items by cosine similarity to the query, highest first. A top_k of 0 keeps everything.
+pub fn rank<T: Embeddable + Clone + Send + Sync>(items: &[T], query: &[f32], top_k: usize) -> Vec<(T, f32)> {
+	let query = RankQuery {
+		query_vec: query,
+		normalized: false,
+		top_k,
+		offset: 0,
+		min_score: f32::NEG_INFINITY,
+		keywords: None,
 	};
-}
\ No newline at end of file
+	rank_with(items, None, &query)
+}
+
+// Everything a ranking needs besides the items themselves
+struct RankQuery<'a> {
+	query_vec: &'a [f32],
+	// Both the query and the items have unit length, see similarity
+	normalized: bool,
+	top_k: usize,
+	offset: usize,
+	min_score: f32,
+	keywords: Option<&'a KeywordQuery>,
+}
+
+fn rank_with<T: Embeddable + Clone + Send + Sync>(items: &[T], index: Option<&ann::IvfIndex>, query: &RankQuery) -> Vec<(T, f32)> {
+	// Sort relevant items by similarity descending
+	finish_ranking(score_items(items, index, query, |_| true), query.offset, query.top_k)
+}
+
+// Scores above min_score with their exact name flag, unsorted. Scores are computed in parallel, collect keeps the
+// input order so sorting afterwards stays deterministic. Items rejected by `keep`, or whose embedding cannot be
+// compared with the query, are dropped rather than ranked last.
+fn score_items<T: Embeddable + Clone + Send + Sync>(items: &[T], index: Option<&ann::IvfIndex>, query: &RankQuery, keep: impl Fn(&T) -> bool + Sync) -> Vec<(T, f32, bool)> {
+	candidate_positions(index, items.len(), query.query_vec)
+		.into_par_iter()
+		.map(|i| &items[i])
+		.filter(|item| keep(item))
+		.filter_map(|item| {
+			let semantic = similarity(query.normalized, query.query_vec, item.embedding())?;
+			let (score, exact) = blend(query.keywords, semantic, item.name(), item.description());
+			log::trace!("{} scored {:.3} (similarity {:.3})", item.name(), score, semantic);
+			Some((item, score, exact))
+		})
+		.filter(|(_, score, _)| *score >= query.min_score)
+		.map(|(item, score, exact)| (item.clone(), score, exact))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fake_files(n: usize) -> Vec<String> {
+		(0..n).map(|i| format!("file_{}.rs", i)).collect()
+	}
+
+	// Embeds every text as [length, 1], canonicalization is never needed here
+	struct LengthEmbedder;
+
+	#[async_trait::async_trait]
+	impl llm::LlmProvider for LengthEmbedder {
+		async fn canonicalize(&self, _code: String) -> Result<String, llm::LlmError> {
+			Err("not used".into())
+		}
+
+		async fn embed(&self, text: String) -> Result<Vec<f32>, llm::LlmError> {
+			Ok(vec![text.len() as f32, 1.0])
+		}
+	}
+
+	#[test]
+	fn chunk_files_covers_every_file_exactly_once() {
+		let files = fake_files(7);
+		let chunks = chunk_files(&files, 4);
+		assert_eq!(chunks.len(), 4);
+		let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+		assert_eq!(sizes, vec![2, 2, 2, 1]);
+		let flattened: Vec<String> = chunks.into_iter().flatten().collect();
+		assert_eq!(flattened, files);
+	}
+
+	#[test]
+	fn chunk_files_with_fewer_files_than_cores() {
+		let files = fake_files(2);
+		let chunks = chunk_files(&files, 8);
+		assert_eq!(chunks, vec![vec![files[0].clone()], vec![files[1].clone()]]);
+		assert!(chunk_files(&[], 4).is_empty());
+	}
+
+	#[test]
+	fn normalized_dot_product_matches_cosine_similarity() {
+		let query = vec![1.0, 2.0, 3.0];
+		let stored = vec![4.0, -1.0, 0.5];
+		let expected = cosine_similarity(&query, &stored).unwrap();
+		let mut code_base = code_base::default();
+		code_base.morphisms.push(llm::Morphism { embedding: stored, ..Default::default() });
+		code_base.normalize_embeddings();
+		let mut normalized_query = query.clone();
+		normalize(&mut normalized_query);
+		let actual = similarity(code_base.normalized, &normalized_query, &code_base.morphisms[0].embedding).unwrap();
+		assert!((expected - actual).abs() < 1e-6);
+	}
+
+	#[test]
+	fn cosine_similarity_rejects_empty_and_mismatched_vectors() {
+		assert_eq!(cosine_similarity(&[], &[]), None);
+		assert_eq!(cosine_similarity(&[1.0, 0.0], &[]), None);
+		assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0, 0.0, 0.0]), None);
+		assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), Some(1.0));
+	}
+
+	#[test]
+	fn link_morphisms_resolves_owners_per_file() {
+		let object = |name: &str, path: &str, methods: &[&str]| llm::Object {
+			name: name.to_string(),
+			file_path: path.to_string(),
+			morphisms: methods.iter().map(|m| m.to_string()).collect(),
+			..Default::default()
+		};
+		let morphism = |name: &str, path: &str| llm::Morphism {
+			name: name.to_string(),
+			file_path: path.to_string(),
+			..Default::default()
+		};
+		let mut code_base = code_base {
+			objects: vec![
+				object("Animal", "a.py", &["__init__", "speak"]),
+				object("Dog", "a.py", &["speak"]),
+				object("Robot", "b.py", &["speak"]),
+			],
+			morphisms: vec![
+				morphism("__init__", "a.py"),
+				morphism("speak", "a.py"),
+				morphism("speak", "a.py"),
+				morphism("speak", "b.py"),
+				morphism("helper", "b.py"),
+			],
+			..Default::default()
+		};
+		code_base.link_morphisms();
+		let owners: Vec<Option<&str>> = code_base.morphisms.iter().map(|m| m.owner_object.as_deref()).collect();
+		assert_eq!(owners, vec![Some("Animal"), Some("Animal"), Some("Dog"), Some("Robot"), None]);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn list_files_survives_symlink_loops() {
+		let root = std::env::temp_dir().join(format!("eve_list_files_{}", std::process::id()));
+		let nested = root.join("src");
+		std::fs::create_dir_all(&nested).unwrap();
+		std::fs::write(nested.join("main.rs"), "fn main() {}").unwrap();
+		// src/loop points back at the root
+		std::os::unix::fs::symlink(&root, nested.join("loop")).unwrap();
+		let files = list_files(root.to_str().unwrap()).unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+		assert_eq!(files, vec![nested.join("main.rs").to_str().unwrap().to_string()]);
+		assert!(list_files("/definitely/not/a/real/dir").is_err());
+	}
+
+	#[test]
+	fn detect_language_uses_the_extension() {
+		assert_eq!(detect_language("src/main.rs").as_deref(), Some("Rust"));
+		assert_eq!(detect_language("tools/Build.PY").as_deref(), Some("Python"));
+		assert_eq!(detect_language("README.md"), None);
+		assert_eq!(detect_language("Makefile"), None);
+	}
+
+	#[test]
+	fn hybrid_ranking_puts_exact_name_matches_first() {
+		let morphism = |name: &str, description: &str, embedding: Vec<f32>| llm::Morphism {
+			name: name.to_string(),
+			description: description.to_string(),
+			embedding,
+			..Default::default()
+		};
+		let code_base = code_base {
+			morphisms: vec![
+				morphism("request_llm", "Sends the code to the model and parses the response.", vec![1.0, 0.0]),
+				morphism("parse_llm_response", "Turns raw JSON into structs.", vec![0.2, 1.0]),
+			],
+			..Default::default()
+		};
+		let query_vec = [1.0, 0.0];
+		let semantic = rank(&code_base.morphisms, &query_vec, 0);
+		assert_eq!(semantic[0].0.name, "request_llm");
+		let keywords = KeywordQuery::new("parse_llm_response", 0.3);
+		let query = RankQuery { query_vec: &query_vec, normalized: false, top_k: 0, offset: 0, min_score: -1.0, keywords: Some(&keywords) };
+		let hybrid = rank_with(&code_base.morphisms, None, &query);
+		assert_eq!(hybrid[0].0.name, "parse_llm_response");
+	}
+
+	#[test]
+	fn merge_replaces_files_with_the_same_path() {
+		let file = |path: &str, description: &str| llm::File {
+			path: path.to_string(),
+			description: description.to_string(),
+			..Default::default()
+		};
+		let object = |name: &str, file_path: &str| llm::Object {
+			name: name.to_string(),
+			file_path: file_path.to_string(),
+			..Default::default()
+		};
+		let mut base = code_base {
+			files: vec![file("a.py", "old"), file("b.py", "kept")],
+			objects: vec![object("Old", "a.py"), object("Kept", "b.py")],
+			..Default::default()
+		};
+		base.merge(code_base {
+			files: vec![file("a.py", "new"), file("c.py", "added")],
+			objects: vec![object("New", "a.py")],
+			..Default::default()
+		});
+		let descriptions: Vec<&str> = base.files.iter().map(|f| f.description.as_str()).collect();
+		assert_eq!(descriptions, vec!["kept", "new", "added"]);
+		let names: Vec<&str> = base.objects.iter().map(|o| o.name.as_str()).collect();
+		assert_eq!(names, vec!["Kept", "New"]);
+	}
+
+	#[test]
+	fn query_result_json_keeps_scores_and_locations() {
+		let result = query_result {
+			relevant_morphisms: vec![(
+				llm::Morphism {
+					name: "bark".to_string(),
+					file_path: "dog.py".to_string(),
+					start_line: 3,
+					end_line: 4,
+					owner_object: Some("Dog".to_string()),
+					embedding: vec![1.0, 0.0],
+					..Default::default()
+				},
+				0.5,
+			)],
+			..Default::default()
+		};
+		let json = result.to_json();
+		assert_eq!(json["files"], serde_json::json!([]));
+		let hit = &json["morphisms"][0];
+		assert_eq!(hit["score"], 0.5);
+		assert_eq!(hit["file_path"], "dog.py");
+		assert_eq!(hit["start_line"], 3);
+		assert_eq!(hit["owner_object"], "Dog");
+		assert!(hit.get("embedding").is_none());
+	}
+
+	#[test]
+	fn retry_failed_embeddings_fills_flagged_records() {
+		let mut base = code_base {
+			objects: vec![
+				llm::Object { description: "abc".to_string(), embedding_failed: true, ..Default::default() },
+				llm::Object { description: "kept".to_string(), embedding: vec![0.0, 1.0], ..Default::default() },
+			],
+			..Default::default()
+		};
+		assert_eq!(base.embedding_failures(), (1, 2));
+		assert_eq!(base.retry_failed_embeddings(&LengthEmbedder), 0);
+		assert_eq!(base.objects[0].embedding, vec![3.0, 1.0]);
+		assert_eq!(base.objects[1].embedding, vec![0.0, 1.0]);
+		assert_eq!(base.embedding_failures(), (0, 2));
+	}
+
+	// Rejects batches the embeddings endpoint would reject and records the size of every other one
+	#[derive(Default)]
+	struct BatchRecorder {
+		batches: std::sync::Mutex<Vec<usize>>,
+	}
+
+	#[async_trait::async_trait]
+	impl llm::LlmProvider for BatchRecorder {
+		async fn canonicalize(&self, _code: String) -> Result<String, llm::LlmError> {
+			Err("not used".into())
+		}
+
+		async fn embed(&self, _text: String) -> Result<Vec<f32>, llm::LlmError> {
+			Err("not used".into())
+		}
+
+		async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, llm::LlmError> {
+			if texts.len() > llm::MAX_EMBEDDING_BATCH {
+				return Err("too many inputs".into());
+			}
+			self.batches.lock().unwrap().push(texts.len());
+			Ok(texts.iter().map(|text| vec![text.len() as f32, 1.0]).collect())
+		}
+	}
+
+	#[test]
+	fn retry_failed_embeddings_sends_bounded_batches() {
+		let failed = llm::Object { description: "abc".to_string(), embedding_failed: true, ..Default::default() };
+		let mut base = code_base { objects: vec![failed; llm::MAX_EMBEDDING_BATCH + 1], ..Default::default() };
+		let provider = BatchRecorder::default();
+		assert_eq!(base.retry_failed_embeddings(&provider), 0);
+		assert_eq!(*provider.batches.lock().unwrap(), vec![llm::MAX_EMBEDDING_BATCH, 1]);
+		assert_eq!(base.objects[llm::MAX_EMBEDDING_BATCH].embedding, vec![3.0, 1.0]);
+	}
+
+	#[test]
+	fn estimate_cost_counts_the_prompt_for_every_chunk() {
+		let dir = std::env::temp_dir().join(format!("eve_estimate_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("main.rs");
+		std::fs::write(&path, "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+		let files = vec![path.to_str().unwrap().to_string(), dir.join("missing.rs").to_str().unwrap().to_string()];
+		let config = llm::LlmConfig::default();
+		let estimate = estimate_cost(&files, &config);
+		assert_eq!(estimate.files, 1);
+		assert_eq!(estimate.requests, 1);
+		assert!(estimate.input_tokens > estimate.output_tokens);
+		assert!(estimate.output_tokens > 0);
+		assert!(estimate.usd.unwrap() > 0.0);
+		let unpriced = llm::LlmConfig { model: "local-model".to_string(), ..Default::default() };
+		assert!(estimate_cost(&files, &unpriced).usd.is_none());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn async_search_runs_on_the_callers_runtime() {
+		let code_base = code_base {
+			files: vec![
+				llm::File { path: "short.rs".to_string(), embedding: vec![1.0, 1.0], ..Default::default() },
+				llm::File { path: "long.rs".to_string(), embedding: vec![5.0, 1.0], ..Default::default() },
+			],
+			..Default::default()
+		};
+		let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+		let result = runtime.block_on(search_codebase_async(&code_base, "query".to_string(), "file", 1, 0.0, &LengthEmbedder));
+		assert_eq!(result.relevant_files.len(), 1);
+		assert_eq!(result.relevant_files[0].0.path, "long.rs");
+	}
+
+	#[test]
+	fn stats_count_languages_and_inbound_dependencies() {
+		let object = |name: &str, dependencies: &[&str]| llm::Object {
+			name: name.to_string(),
+			dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+			..Default::default()
+		};
+		let code_base = code_base {
+			files: vec![
+				llm::File { language: "Rust".to_string(), size: 120, ..Default::default() },
+				llm::File { language: "Python".to_string(), size: 30, ..Default::default() },
+				llm::File { language: "Rust".to_string(), size: 50, ..Default::default() },
+			],
+			objects: vec![object("Config", &[]), object("Client", &["Config", "reqwest"]), object("Server", &["Config", "Client"])],
+			morphisms: vec![llm::Morphism { dependencies: vec!["Client".to_string(), "Config".to_string()], ..Default::default() }],
+			..Default::default()
+		};
+		let stats = code_base.stats();
+		assert_eq!((stats.files, stats.objects, stats.morphisms), (3, 3, 1));
+		assert_eq!(stats.total_bytes, 200);
+		assert_eq!(stats.languages, vec![("Rust".to_string(), 2), ("Python".to_string(), 1)]);
+		assert_eq!(stats.most_depended_upon, vec![("Config".to_string(), 3), ("Client".to_string(), 2)]);
+	}
+
+	#[test]
+	fn summary_comes_from_the_llm_or_falls_back_to_file_descriptions() {
+		let mut code_base = code_base {
+			files: vec![
+				llm::File { path: "src/main.rs".to_string(), language: "Rust".to_string(), description: "Command line entry point.".to_string(), ..Default::default() },
+				llm::File { path: "build.sh".to_string(), description: "Builds the release binary.\n".to_string(), ..Default::default() },
+			],
+			..Default::default()
+		};
+		let overview = "src/main.rs (Rust): Command line entry point.\nbuild.sh (unknown): Builds the release binary.\n";
+		code_base.summarize(None);
+		assert_eq!(code_base.summary, overview);
+
+		let summarizer = crate::mock::MockProvider::new().with_completion(" A command line tool.\n");
+		code_base.summarize(Some(&summarizer));
+		assert_eq!(code_base.summary, "A command line tool.");
+
+		// No completion configured, the request fails and the overview is kept
+		code_base.summarize(Some(&crate::mock::MockProvider::new()));
+		assert_eq!(code_base.summary, overview);
+	}
+
+	#[test]
+	fn binary_and_oversized_files_are_skipped() {
+		let dir = std::env::temp_dir().join(format!("eve_skip_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let text = dir.join("lib.rs");
+		let binary = dir.join("logo.rs");
+		std::fs::write(&text, "pub fn answer() -> u32 { 42 }\n").unwrap();
+		std::fs::write(&binary, [0x89, b'P', b'N', b'G', 0, 0, 0, 13]).unwrap();
+		let config = llm::LlmConfig::default();
+		assert_eq!(should_skip(text.to_str().unwrap(), &config), None);
+		assert_eq!(should_skip(binary.to_str().unwrap(), &config), Some(SkipReason::Binary));
+		let tiny = llm::LlmConfig { max_file_bytes: 10, ..Default::default() };
+		assert_eq!(should_skip(text.to_str().unwrap(), &tiny), Some(SkipReason::TooLarge(30)));
+		assert!(index_file(binary.to_str().unwrap(), &LengthEmbedder, &config).is_err());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn dedup_collapses_similar_records_with_the_same_name() {
+		let object = |name: &str, file_path: &str, embedding: Vec<f32>| llm::Object {
+			name: name.to_string(),
+			file_path: file_path.to_string(),
+			embedding,
+			..Default::default()
+		};
+		let mut base = code_base {
+			objects: vec![
+				object("Config", "a.rs", vec![1.0, 0.0]),
+				object("Config", "b.rs", vec![0.99, 0.05]),
+				object("Config", "c.rs", vec![0.0, 1.0]),
+				object("Client", "b.rs", vec![1.0, 0.0]),
+				object("Config", "d.rs", Vec::new()),
+			],
+			..Default::default()
+		};
+		assert_eq!(base.dedup(DEFAULT_DEDUP_THRESHOLD), 1);
+		let files: Vec<&str> = base.objects.iter().map(|o| o.file_path.as_str()).collect();
+		assert_eq!(files, vec!["a.rs", "c.rs", "b.rs", "d.rs"]);
+		assert_eq!(base.objects[0].duplicate_file_paths, vec!["b.rs"]);
+		assert_eq!(base.dedup(0.0), 1);
+		assert_eq!(base.objects[0].duplicate_file_paths, vec!["b.rs", "c.rs"]);
+	}
+
+	#[test]
+	fn search_ranks_all_requested_types_together() {
+		let code_base = code_base {
+			files: vec![llm::File { path: "a.rs".to_string(), embedding: vec![2.0, 1.0], ..Default::default() }],
+			objects: vec![llm::Object { name: "Config".to_string(), embedding: vec![6.0, 1.0], ..Default::default() }],
+			morphisms: vec![llm::Morphism { name: "load".to_string(), embedding: vec![3.5, 1.0], ..Default::default() }],
+			..Default::default()
+		};
+		// LengthEmbedder turns the five letter query into [5, 1]
+		let options = SearchOptions { top_k: 2, ..Default::default() };
+		let hits = search(&code_base, "query", &options, &LengthEmbedder);
+		assert_eq!(hits.len(), 2);
+		assert!(matches!(&hits[0].hit, Hit::Object(obj) if obj.name == "Config"));
+		assert!(matches!(&hits[1].hit, Hit::Morphism(morph) if morph.name == "load"));
+		assert!(hits[0].score >= hits[1].score);
+		let files_only = SearchOptions { types: vec![ItemType::File], ..Default::default() };
+		let hits = search(&code_base, "query", &files_only, &LengthEmbedder);
+		assert!(matches!(&hits[..], [SearchHit { hit: Hit::File(_), .. }]));
+	}
+
+	#[test]
+	fn language_and_type_filters_apply_before_ranking() {
+		let object = |name: &str, obj_type: &str, file_path: &str, embedding: Vec<f32>| llm::Object {
+			name: name.to_string(),
+			obj_type: obj_type.to_string(),
+			file_path: file_path.to_string(),
+			embedding,
+			..Default::default()
+		};
+		let code_base = code_base {
+			files: vec![
+				llm::File { path: "src/lib.rs".to_string(), language: "Rust".to_string(), embedding: vec![5.0, 1.0], ..Default::default() },
+				llm::File { path: "app/models.py".to_string(), language: "Python".to_string(), embedding: vec![5.0, 1.0], ..Default::default() },
+			],
+			objects: vec![
+				object("Model", "class", "app/models.py", vec![5.0, 1.0]),
+				object("Config", "struct", "src/lib.rs", vec![1.0, 1.0]),
+				object("Kind", "enum", "src/lib.rs", vec![5.0, 1.0]),
+				// Not in files, the language comes from the extension
+				object("Token", "struct", "src/lexer.rs", vec![0.0, 1.0]),
+			],
+			morphisms: vec![llm::Morphism { name: "parse".to_string(), morph_type: "function".to_string(), file_path: "src/lib.rs".to_string(), embedding: vec![5.0, 1.0], ..Default::default() }],
+			..Default::default()
+		};
+		let names = |options: &SearchOptions| -> Vec<String> {
+			search(&code_base, "query", options, &LengthEmbedder)
+				.into_iter()
+				.map(|hit| match hit.hit {
+					Hit::File(file) => file.path,
+					Hit::Object(obj) => obj.name,
+					Hit::Morphism(morph) => morph.name,
+				})
+				.collect()
+		};
+		// The closer Python class and Rust enum would otherwise take the only slot
+		let rust_structs = SearchOptions { top_k: 1, language: Some("rust".to_string()), obj_type: Some("Struct".to_string()), ..Default::default() };
+		assert_eq!(names(&rust_structs), vec!["Config"]);
+		let rust_structs = SearchOptions { top_k: 0, ..rust_structs };
+		assert_eq!(names(&rust_structs), vec!["Config", "Token"]);
+		let functions = SearchOptions { obj_type: Some("function".to_string()), ..Default::default() };
+		assert_eq!(names(&functions), vec!["parse"]);
+		let python = SearchOptions { types: vec![ItemType::File, ItemType::Object], language: Some("Python".to_string()), ..Default::default() };
+		assert_eq!(names(&python), vec!["app/models.py", "Model"]);
+	}
+
+	#[test]
+	fn rerank_reorders_the_top_candidates_only() {
+		let code_base = code_base {
+			objects: (0..4).map(|i| llm::Object { name: format!("item_{}", i), embedding: vec![5.0 - i as f32, 1.0], ..Default::default() }).collect(),
+			..Default::default()
+		};
+		let names = |hits: Vec<SearchHit>| -> Vec<String> { hits.iter().map(|hit| hit.name().to_string()).collect() };
+		let provider = crate::mock::MockProvider::new().with_embedding("query", vec![5.0, 1.0]).with_completion("[2, 1, 0, 3]");
+		let options = SearchOptions { rerank: true, ..Default::default() };
+		assert_eq!(names(search(&code_base, "query", &options, &provider)), vec!["item_2", "item_1", "item_0", "item_3"]);
+		let paged = SearchOptions { top_k: 2, offset: 1, ..options.clone() };
+		assert_eq!(names(search(&code_base, "query", &paged, &provider)), vec!["item_1", "item_0"]);
+
+		// Only the first two hits are sent, the third index in the answer is ignored
+		let hits: Vec<(llm::Object, f32)> = code_base.objects.iter().cloned().map(|obj| (obj, 1.0)).collect();
+		let reranked = llm::runtime().block_on(rerank("query", hits.clone(), 2, &provider));
+		let order: Vec<&str> = reranked.iter().map(|(obj, _)| obj.name.as_str()).collect();
+		assert_eq!(order, vec!["item_1", "item_0", "item_2", "item_3"]);
+		// No completion, the similarity order is kept
+		let unchanged = llm::runtime().block_on(rerank("query", hits, 4, &crate::mock::MockProvider::new()));
+		assert_eq!(unchanged[0].0.name, "item_0");
+	}
+
+	#[test]
+	fn offset_pages_through_the_ranking() {
+		let code_base = code_base {
+			files: vec![llm::File { path: "a.rs".to_string(), embedding: vec![2.0, 1.0], ..Default::default() }],
+			objects: vec![llm::Object { name: "Config".to_string(), embedding: vec![6.0, 1.0], ..Default::default() }],
+			morphisms: vec![llm::Morphism { name: "load".to_string(), embedding: vec![3.5, 1.0], ..Default::default() }],
+			..Default::default()
+		};
+		let second = SearchOptions { top_k: 1, offset: 1, ..Default::default() };
+		let hits = search(&code_base, "query", &second, &LengthEmbedder);
+		assert!(matches!(&hits[..], [SearchHit { hit: Hit::Morphism(morph), .. }] if morph.name == "load"));
+		let rest = SearchOptions { top_k: 0, offset: 1, ..Default::default() };
+		assert_eq!(search(&code_base, "query", &rest, &LengthEmbedder).len(), 2);
+		for offset in [3, 100] {
+			let past_the_end = SearchOptions { offset, ..Default::default() };
+			assert!(search(&code_base, "query", &past_the_end, &LengthEmbedder).is_empty());
+		}
+		assert_eq!(page(vec![1, 2, 3, 4, 5], 2, 2), vec![3, 4]);
+	}
+
+	#[test]
+	fn list_files_returns_sorted_paths() {
+		let dir = std::env::temp_dir().join(format!("eve_sorted_test_{}", std::process::id()));
+		for sub in ["b", "a/nested"] {
+			std::fs::create_dir_all(dir.join(sub)).unwrap();
+		}
+		for file in ["z.rs", "b/y.rs", "a/x.rs", "a/nested/w.rs", "m.rs"] {
+			std::fs::write(dir.join(file), "").unwrap();
+		}
+		let root = dir.to_str().unwrap();
+		let relative = |files: Vec<String>| -> Vec<String> {
+			files.iter().map(|f| f.strip_prefix(root).unwrap().trim_start_matches('/').to_string()).collect()
+		};
+		let expected = vec!["a/nested/w.rs", "a/x.rs", "b/y.rs", "m.rs", "z.rs"];
+		assert_eq!(relative(list_files(root).unwrap()), expected);
+		assert_eq!(relative(list_files_gitignore(root).unwrap()), expected);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn list_files_filtered_keeps_matching_extensions_outside_ignored_dirs() {
+		let dir = std::env::temp_dir().join(format!("eve_filtered_test_{}", std::process::id()));
+		for sub in ["src", "target/debug", ".git"] {
+			std::fs::create_dir_all(dir.join(sub)).unwrap();
+		}
+		for file in ["src/lib.rs", "src/notes.txt", "README.md", "build.py", "target/debug/out.rs", ".git/HEAD", ".git/hook.py"] {
+			std::fs::write(dir.join(file), "").unwrap();
+		}
+		let root = dir.to_str().unwrap();
+		let relative = |files: Vec<String>| -> Vec<String> {
+			files.iter().map(|f| f.strip_prefix(root).unwrap().trim_start_matches('/').to_string()).collect()
+		};
+		assert_eq!(relative(list_files_filtered(root, &["rs", "py"], DEFAULT_IGNORE_DIRS).unwrap()), vec!["build.py", "src/lib.rs"]);
+		// Without ignore dirs the extension filter alone still applies
+		assert_eq!(relative(list_files_filtered(root, &["rs"], &[]).unwrap()), vec!["src/lib.rs", "target/debug/out.rs"]);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn list_files_gitignore_applies_nested_rules_and_negations() {
+		let dir = std::env::temp_dir().join(format!("eve_gitignore_test_{}", std::process::id()));
+		for sub in ["build", "sub/deeper", ".git"] {
+			std::fs::create_dir_all(dir.join(sub)).unwrap();
+		}
+		std::fs::write(dir.join(".gitignore"), "*.log\nbuild/\n").unwrap();
+		// Nested rules apply below their directory only, the negation brings one file back
+		std::fs::write(dir.join("sub/.gitignore"), "*.rs\n!keep.rs\n").unwrap();
+		for file in ["main.rs", "debug.log", "build/out.rs", "sub/drop.rs", "sub/keep.rs", "sub/notes.md", "sub/deeper/also.rs", "sub/deeper/trace.log", ".git/HEAD"] {
+			std::fs::write(dir.join(file), "").unwrap();
+		}
+		let root = dir.to_str().unwrap();
+		let relative = |files: Vec<String>| -> Vec<String> {
+			files.iter().map(|f| f.strip_prefix(root).unwrap().trim_start_matches('/').to_string()).collect()
+		};
+		assert_eq!(relative(list_files_gitignore(root).unwrap()), vec![".gitignore", "main.rs", "sub/.gitignore", "sub/keep.rs", "sub/notes.md"]);
+		assert_eq!(relative(list_files_gitignore_filtered(root, &["rs"], &["sub"]).unwrap()), vec!["main.rs"]);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	// Records progress calls so tests can check what a run reported
+	#[derive(Default)]
+	struct RecordingReporter {
+		indexed: std::sync::Mutex<Vec<(usize, usize)>>,
+		failed: std::sync::Mutex<Vec<String>>,
+	}
+
+	impl ProgressReporter for RecordingReporter {
+		fn on_file_indexed(&self, done: usize, total: usize) {
+			self.indexed.lock().unwrap().push((done, total));
+		}
+
+		fn on_file_failed(&self, path: &str, _err: &str) {
+			self.failed.lock().unwrap().push(path.to_string());
+		}
+
+		fn on_file_skipped(&self, _path: &str, _reason: &SkipReason) {}
+
+		fn on_embeddings_failed(&self, _failed: usize, _total: usize) {}
+	}
+
+	fn canned_response(function: &str, dependencies_key: &str) -> String {
+		format!(
+			r#"{{"General Information": {{"Language": "Python", "Description": "Defines {f}."}},
+			"Objects": [],
+			"Morphisms": [{{"name": "{f}", "type": "function", "{d}": ["os"], "description": "The {f} helper."}}]}}"#,
+			f = function,
+			d = dependencies_key
+		)
+	}
+
+	#[test]
+	fn search_codebase_ranks_by_cosine_similarity() {
+		let morphism = |name: &str, embedding: Vec<f32>| llm::Morphism { name: name.to_string(), embedding, ..Default::default() };
+		let mut code_base = code_base {
+			morphisms: vec![
+				morphism("far", vec![0.0, 1.0]),
+				morphism("close", vec![0.9, 0.1]),
+				morphism("exact", vec![2.0, 0.0]),
+				morphism("opposite", vec![-1.0, 0.0]),
+			],
+			..Default::default()
+		};
+		code_base.normalize_embeddings();
+		let provider = crate::mock::MockProvider::new().with_embedding("find it", vec![1.0, 0.0]);
+		let result = search_codebase(&code_base, "find it".to_string(), "morphism", 0, -1.0, &provider);
+		let names: Vec<&str> = result.relevant_morphisms.iter().map(|(m, _)| m.name.as_str()).collect();
+		assert_eq!(names, vec!["exact", "close", "far", "opposite"]);
+		assert!((result.relevant_morphisms[0].1 - 1.0).abs() < 1e-6);
+		assert!(result.relevant_morphisms.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+		assert!(result.relevant_files.is_empty() && result.relevant_objects.is_empty());
+	}
+
+	#[test]
+	fn hybrid_search_matches_keywords_against_the_typed_query() {
+		let morphism = |name: &str| llm::Morphism { name: name.to_string(), embedding: vec![1.0, 0.0], ..Default::default() };
+		let code_base = code_base { morphisms: vec![morphism("alpha"), morphism("beta")], ..Default::default() };
+		// The expanded text names both, only the typed one decides the exact match
+		let provider = crate::mock::MockProvider::new().with_embedding("alpha beta", vec![1.0, 0.0]);
+		for typed in ["alpha", "beta"] {
+			let result = search_codebase_hybrid(&code_base, "alpha beta".to_string(), typed, "morphism", 1, -1.0, 0.5, &provider);
+			assert_eq!(result.relevant_morphisms[0].0.name, typed);
+		}
+	}
+
+	#[test]
+	fn index_files_covers_every_file_across_workers() {
+		let dir = std::env::temp_dir().join(format!("eve_mock_index_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let mut provider = crate::mock::MockProvider::new();
+		let mut files = Vec::new();
+		for i in 0..7 {
+			let function = format!("helper_{}", i);
+			let path = dir.join(format!("file_{}.py", i));
+			std::fs::write(&path, format!("def {}():\n    return {}\n", function, i)).unwrap();
+			provider = provider.with_response(&function, &canned_response(&function, "Dependencies"));
+			files.push(path.to_str().unwrap().to_string());
+		}
+		let config = llm::LlmConfig { max_concurrent_requests: 4, ..Default::default() };
+		let reporter = std::sync::Arc::new(RecordingReporter::default());
+		let code_base = index_files(&files, &provider, &config, reporter.clone());
+		let paths: Vec<&str> = code_base.files.iter().map(|f| f.path.as_str()).collect();
+		assert_eq!(paths, files.iter().map(|f| f.as_str()).collect::<Vec<_>>());
+		let names: Vec<&str> = code_base.morphisms.iter().map(|m| m.name.as_str()).collect();
+		assert_eq!(names, (0..7).map(|i| format!("helper_{}", i)).collect::<Vec<_>>());
+		assert_eq!(code_base.morphisms[3].start_line, 1);
+		assert_eq!(provider.canonicalize_calls(), 7);
+		assert!(code_base.normalized);
+		assert_eq!(code_base.embedding_failures(), (0, 14));
+		assert!(reporter.failed.lock().unwrap().is_empty());
+		assert_eq!(reporter.indexed.lock().unwrap().last(), Some(&(7, 7)));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn indexing_resumes_from_a_checkpoint() {
+		let _runs = checkpoint::TEST_RUNS.lock().unwrap_or_else(|err| err.into_inner());
+		let dir = std::env::temp_dir().join(format!("eve_resume_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let mut files = Vec::new();
+		for name in ["done", "edited", "todo"] {
+			let path = dir.join(format!("{}.py", name));
+			std::fs::write(&path, format!("def {}():\n    pass\n", name)).unwrap();
+			files.push(path.to_str().unwrap().to_string());
+		}
+		// Became binary since the checkpoint, its records are dropped and it does not count towards the progress
+		let blob = dir.join("blob.py").to_str().unwrap().to_string();
+		std::fs::write(&blob, "\0\0").unwrap();
+		files.push(blob.clone());
+		// A previous run got through the first two files and the blob, plus one that is not part of this run. The
+		// second file was edited since.
+		let config = llm::LlmConfig::default();
+		let checkpoint = checkpoint::Checkpoint::new(dir.join("checkpoint.json"), 1);
+		let record = |path: &str| llm::File { path: path.to_string(), description: "From before.".to_string(), ..Default::default() };
+		let previous = code_base {
+			files: vec![record(&files[0]), record(&files[1]), record(&blob), record("elsewhere.py")],
+			morphisms: vec![llm::Morphism { name: "done".to_string(), file_path: files[0].clone(), ..Default::default() }],
+			..Default::default()
+		};
+		let hashes = std::collections::HashMap::from([
+			(files[0].clone(), checkpoint::file_hash(&files[0], &config).unwrap()),
+			(files[1].clone(), "stale".to_string()),
+			(blob.clone(), checkpoint::file_hash(&blob, &config).unwrap()),
+		]);
+		checkpoint.save(&previous, &hashes).unwrap();
+
+		let provider = crate::mock::MockProvider::new()
+			.with_response("todo", &canned_response("todo", "Dependencies"))
+			.with_response("edited", &canned_response("edited", "Dependencies"));
+		let reporter = std::sync::Arc::new(RecordingReporter::default());
+		let code_base = index_files_with_checkpoint(&files, &provider, &config, reporter.clone(), Some(&checkpoint));
+		assert_eq!(provider.canonicalize_calls(), 2);
+		let paths: Vec<&str> = code_base.files.iter().map(|f| f.path.as_str()).collect();
+		assert_eq!(paths, vec![files[0].as_str(), files[1].as_str(), files[2].as_str()]);
+		assert_eq!(code_base.files[0].description, "From before.");
+		assert_eq!(code_base.files[1].description, "Defines edited.");
+		let names: Vec<&str> = code_base.morphisms.iter().map(|m| m.name.as_str()).collect();
+		assert_eq!(names, vec!["done", "edited", "todo"]);
+		assert_eq!(reporter.indexed.lock().unwrap().last(), Some(&(3, 3)));
+		// The run completed, so the next one starts over
+		assert!(!checkpoint.exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn failed_files_are_collected_in_input_order() {
+		let dir = std::env::temp_dir().join(format!("eve_failures_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		// Only the second file has a canned response, the others fail to canonicalize
+		let provider = crate::mock::MockProvider::new().with_response("known", &canned_response("known", "Dependencies"));
+		let mut files = Vec::new();
+		for name in ["a", "known", "c"] {
+			let path = dir.join(format!("{}.py", name));
+			std::fs::write(&path, format!("def {}():\n    pass\n", name)).unwrap();
+			files.push(path.to_str().unwrap().to_string());
+		}
+		let config = llm::LlmConfig { max_concurrent_requests: 3, ..Default::default() };
+		let mut code_base = index_files(&files, &provider, &config, std::sync::Arc::new(RecordingReporter::default()));
+		let failed: Vec<&str> = code_base.failures.iter().map(|(path, _)| path.as_str()).collect();
+		assert_eq!(failed, vec![files[0].as_str(), files[2].as_str()]);
+		let report = code_base.failure_report().unwrap();
+		assert!(report.starts_with("Indexed 1/3 files, 2 failed:\n"));
+		assert!(report.contains(&format!("  {}: No canned response matches this code\n", files[2])));
+
+		code_base.remove_file(&files[0]);
+		code_base.merge(code_base::default());
+		assert_eq!(code_base.failures.len(), 1);
+		code_base.merge(code_base { files: vec![llm::File { path: files[2].clone(), ..Default::default() }], ..Default::default() });
+		assert!(code_base.failure_report().is_none());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn index_file_reads_dependencies_in_either_casing() {
+		let dir = std::env::temp_dir().join(format!("eve_mock_casing_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let config = llm::LlmConfig::default();
+		for (function, key) in [("upper", "Dependencies"), ("lower", "dependencies")] {
+			let path = dir.join(format!("{}.py", function));
+			std::fs::write(&path, format!("def {}():\n    pass\n", function)).unwrap();
+			let provider = crate::mock::MockProvider::new().with_response(function, &canned_response(function, key));
+			let (_, _, morphisms) = index_file(path.to_str().unwrap(), &provider, &config).unwrap();
+			assert_eq!(morphisms[0].dependencies, vec!["os"]);
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn simd_dot_product_matches_the_scalar_loop() {
+		let a: Vec<f32> = (0..3072).map(|i| ((i * 37) % 101) as f32 / 101.0 - 0.5).collect();
+		let b: Vec<f32> = (0..3072).map(|i| ((i * 53) % 97) as f32 / 97.0 - 0.5).collect();
+		let simd = dot_product(&a, &b);
+		let scalar = dot_product_scalar(&a, &b);
+		assert!((simd - scalar).abs() < 1e-3 * scalar.abs().max(1.0));
+		// Lengths that do not fill whole lanes go through the scalar loop
+		assert_eq!(dot_product(&a[..7], &b[..7]), dot_product_scalar(&a[..7], &b[..7]));
+	}
+}
@@ -0,0 +1,13 @@
+// Library entry point so other programs can index a directory without going through the CLI
+pub mod ann;
+pub mod cache;
+pub mod checkpoint;
+pub mod graph;
+pub mod indexer;
+pub mod llm;
+pub mod markdown;
+// Offline test provider, only built for the crate's own tests or with the "mock" feature
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod store;
+pub mod watch;
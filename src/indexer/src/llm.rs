@@ -1,11 +1,20 @@
-// We will interact with an LLM using the openAI endpoint.
-use reqwest;
+// We will interact with an LLM using the openAI endpoint, or any other backend implementing LlmProvider.
 use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
-use tokio;
-use serde_json;
 use std::fmt;
+use std::sync::OnceLock;
 
-#[derive(Debug, Clone, Default)]
+// Errors from the provider layer are sent across worker threads, so they must be Send + Sync
+pub type LlmError = Box<dyn std::error::Error + Send + Sync>;
+
+// Every blocking call site shares this runtime, building one per request is slow and burns threads
+static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+
+pub fn runtime() -> &'static tokio::runtime::Runtime {
+    RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().expect("Failed to build Tokio runtime"))
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct File{
     pub size: u64,
     pub name: String,
@@ -13,13 +22,16 @@ pub struct File{
     pub language: String,
     pub description: String,
     pub embedding: Vec<f32>,
+    // Set when the description could not be embedded, such records never match a search
+    pub embedding_failed: bool,
 }
 impl fmt::Display for File {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "File(name: {}, path: {}, language: {}, description: {})", self.name, self.path, self.language, self.description)
     }
 }
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct Object {
     pub name: String,
     pub obj_type: String,
@@ -27,6 +39,13 @@ pub struct Object {
     pub dependencies: Vec<String>,
     pub description: String,
     pub embedding: Vec<f32>,
+    pub embedding_failed: bool,
+    // Where the definition lives, lines are 1-based and inclusive, 0 when it could not be located
+    pub file_path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    // Other files holding the same definition, filled in when code_base::dedup collapses duplicates
+    pub duplicate_file_paths: Vec<String>,
 }
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -41,13 +60,22 @@ impl fmt::Display for Object {
         )
     }
 }
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct Morphism {
     pub name: String,
     pub morph_type: String,
     pub dependencies: Vec<String>,
     pub description: String,
     pub embedding: Vec<f32>,
+    pub embedding_failed: bool,
+    // Same convention as Object, 1-based inclusive lines or 0 when unknown
+    pub file_path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub duplicate_file_paths: Vec<String>,
+    // Name of the object declaring this morphism, resolved by code_base::link_morphisms
+    pub owner_object: Option<String>,
 }
 impl fmt::Display for Morphism {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -62,11 +90,262 @@ impl fmt::Display for Morphism {
     }
 }
 
-pub async fn request_llm(input : String) -> Result<String, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let api_key = std::env::var("OPENAI_API_KEY")?;
-    let response = "https://api.openai.com/v1/responses";
-    let base_prompt = r#"You are a code canonicalizer, you will receive code snippets and you wil output the canonicalized version of the code. Return a json of object, function relationships
+// How a file is cut into canonicalization requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkMode {
+    // One request per file, split only when it would not fit max_chunk_tokens
+    #[default]
+    WholeFile,
+    // One request per top-level definition, more calls but tighter descriptions and more specific embeddings
+    PerSymbol,
+}
+
+// Settings shared by every request made to the LLM provider
+#[derive(Debug, Clone)]
+pub struct LlmConfig {
+    // Model used to canonicalize source files
+    pub model: String,
+    pub embedding_model: String,
+    // Total tries per request, including the first one
+    pub max_attempts: u32,
+    // Delay before the first retry, doubled after every further failure
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Files estimated above this many tokens are canonicalized in several requests
+    pub max_chunk_tokens: usize,
+    pub chunk_mode: ChunkMode,
+    // Upper bound on requests in flight at once while indexing, keep it under the account rate limit
+    pub max_concurrent_requests: usize,
+    // Ask for JSON constrained to the canonicalization schema, turn off for providers without JSON mode
+    pub structured_output: bool,
+    // Larger files are skipped before reading, they are usually generated or data rather than code
+    pub max_file_bytes: u64,
+    // Whole request deadline, a stalled connection fails after this long and is retried like a connection error.
+    // 0 waits forever.
+    pub request_timeout_secs: u64,
+    // Instructions sent ahead of every file, the source code is appended right after them. Defaults to BASE_PROMPT,
+    // replace it to add examples in the languages being indexed or tighten the expected JSON.
+    pub prompt: String,
+}
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            model: "gpt-4.1-mini".to_string(),
+            embedding_model: "text-embedding-3-large".to_string(),
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            max_chunk_tokens: 16_000,
+            chunk_mode: ChunkMode::WholeFile,
+            max_concurrent_requests: 8,
+            structured_output: true,
+            max_file_bytes: 1_000_000,
+            request_timeout_secs: 60,
+            prompt: BASE_PROMPT.to_string(),
+        }
+    }
+}
+
+// Every provider request goes through a client built here, so the timeout applies to all of them
+pub(crate) fn http_client(config: &LlmConfig) -> Result<reqwest::Client, LlmError> {
+    let mut builder = reqwest::Client::builder();
+    if config.request_timeout_secs > 0 {
+        builder = builder.timeout(std::time::Duration::from_secs(config.request_timeout_secs));
+    }
+    Ok(builder.build()?)
+}
+
+// Rate limits and server errors are worth retrying, anything else will fail the same way again
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn backoff_delay(config: &LlmConfig, attempt: u32) -> std::time::Duration {
+    let factor = 1u64 << (attempt.saturating_sub(1)).min(20);
+    let delay_ms = config.initial_backoff_ms.saturating_mul(factor).min(config.max_backoff_ms);
+    std::time::Duration::from_millis(delay_ms)
+}
+
+// Retry-After is given in whole seconds by OpenAI. It is capped at max_backoff_ms like any other delay, a server
+// asking for an hour would otherwise park the worker that long.
+fn retry_after(res: &reqwest::Response, config: &LlmConfig) -> Option<std::time::Duration> {
+    let value = res.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, config)
+}
+
+fn parse_retry_after(value: &str, config: &LlmConfig) -> Option<std::time::Duration> {
+    let delay = std::time::Duration::from_secs(value.trim().parse::<u64>().ok()?);
+    Some(delay.min(std::time::Duration::from_millis(config.max_backoff_ms)))
+}
+
+// Sends the request produced by `build`, retrying rate limits, server errors, connection failures and timeouts with
+// exponential backoff. The final error reports how many attempts were made.
+pub(crate) async fn send_with_retry<F>(build: F, config: &LlmConfig) -> Result<reqwest::Response, LlmError>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    let max_attempts = config.max_attempts.max(1);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let delay = match build().send().await {
+            Ok(res) => {
+                let status = res.status();
+                if status.is_success() {
+                    return Ok(res);
+                }
+                if !is_retryable_status(status) || attempt >= max_attempts {
+                    return Err(format!("Request failed with status: {} after {} attempt(s)", status, attempt).into());
+                }
+                let delay = retry_after(&res, config).unwrap_or_else(|| backoff_delay(config, attempt));
+                log::debug!("Request failed with status {}, retrying in {:?} (attempt {} of {})", status, delay, attempt, max_attempts);
+                delay
+            }
+            Err(err) => {
+                if !(err.is_connect() || err.is_timeout()) || attempt >= max_attempts {
+                    return Err(format!("Request failed after {} attempt(s): {}", attempt, err).into());
+                }
+                let delay = backoff_delay(config, attempt);
+                log::debug!("Request failed: {}, retrying in {:?} (attempt {} of {})", err, delay, attempt, max_attempts);
+                delay
+            }
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// A backend able to canonicalize source code and embed text. parse_llm_response and the indexer only see the
+// normalized JSON returned by canonicalize, so adding a provider does not touch them.
+#[async_trait::async_trait]
+pub trait LlmProvider: Send + Sync {
+    // Returns the canonicalization JSON (General Information / Objects / Morphisms) for a piece of source code
+    async fn canonicalize(&self, code: String) -> Result<String, LlmError>;
+    async fn embed(&self, text: String) -> Result<Vec<f32>, LlmError>;
+    // Free-form answer to a prompt, used for small helper calls such as query expansion
+    async fn complete(&self, _prompt: String) -> Result<String, LlmError> {
+        Err("This provider does not support free-form completions".into())
+    }
+    // Providers with a batch endpoint should override this, the result must stay aligned with `texts`
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for text in texts {
+            embeddings.push(if text.is_empty() { Vec::new() } else { self.embed(text).await? });
+        }
+        Ok(embeddings)
+    }
+}
+
+// OpenAI responses endpoint for canonicalization and the embeddings endpoint for vectors
+pub struct OpenAiProvider {
+    pub config: LlmConfig,
+}
+
+impl OpenAiProvider {
+    pub fn new(config: LlmConfig) -> Self {
+        OpenAiProvider { config }
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn canonicalize(&self, code: String) -> Result<String, LlmError> {
+        request_llm(code, &self.config).await
+    }
+
+    async fn complete(&self, prompt: String) -> Result<String, LlmError> {
+        let body = serde_json::json!({
+            "model": self.config.model,
+            "input": prompt,
+        });
+        post_responses(&body, &self.config).await
+    }
+
+    async fn embed(&self, text: String) -> Result<Vec<f32>, LlmError> {
+        get_embeddings(text, &self.config).await
+    }
+
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        get_embeddings_batch(texts, &self.config).await
+    }
+}
+
+// Claude through the Anthropic messages API. Anthropic has no embeddings endpoint, so embedding requests are
+// forwarded to a second provider.
+pub struct AnthropicProvider {
+    pub config: LlmConfig,
+    pub embedder: Box<dyn LlmProvider>,
+}
+
+impl AnthropicProvider {
+    pub fn new(config: LlmConfig, embedder: Box<dyn LlmProvider>) -> Self {
+        AnthropicProvider { config, embedder }
+    }
+
+    // Sends a single user message and returns the text of the reply
+    async fn send_message(&self, content: String) -> Result<String, LlmError> {
+        let client = http_client(&self.config)?;
+        let api_key = std::env::var("ANTHROPIC_API_KEY")?;
+        let body = serde_json::json!({
+            "model": self.config.model,
+            "max_tokens": 8192,
+            "messages": [{ "role": "user", "content": content }],
+        });
+        let res = send_with_retry(|| {
+            client.post("https://api.anthropic.com/v1/messages")
+                .header("x-api-key", api_key.as_str())
+                .header("anthropic-version", "2023-06-01")
+                .header(CONTENT_TYPE, "application/json")
+                .json(&body)
+        }, &self.config).await?;
+        let res_json: serde_json::Value = res.json().await?;
+        let text = res_json["content"]
+            .as_array()
+            .and_then(|parts| parts.iter().find(|part| part["type"] == "text"))
+            .and_then(|part| part["text"].as_str())
+            .ok_or("No text content in response")?;
+        Ok(text.to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn canonicalize(&self, code: String) -> Result<String, LlmError> {
+        let text = self.send_message(format!("{}{}", self.config.prompt, code)).await?;
+        // The messages API has no JSON mode here, so the document is cut out of the reply
+        extract_json(&text)
+    }
+
+    async fn complete(&self, prompt: String) -> Result<String, LlmError> {
+        self.send_message(prompt).await
+    }
+
+    async fn embed(&self, text: String) -> Result<Vec<f32>, LlmError> {
+        self.embedder.embed(text).await
+    }
+
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        self.embedder.embed_batch(texts).await
+    }
+}
+
+// Everything extracted from one source file
+pub type ParsedFile = (File, Vec<Object>, Vec<Morphism>);
+
+// Raised when the canonicalizer output for a file cannot be parsed as JSON
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub file_path: String,
+    pub message: String,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to parse LLM response for {}: {}", self.file_path, self.message)
+    }
+}
+impl std::error::Error for ParseError {}
+
+// Default instructions sent ahead of every file, the source code is appended after "Input :"
+pub const BASE_PROMPT: &str = r#"You are a code canonicalizer, you will receive code snippets and you wil output the canonicalized version of the code. Return a json of object, function relationships
     Objects are classes, structs, interfaces, not imports. Morphisms are functions, methods, procedures. You will identify the objects and morphisms in the code and their relationships.
     Note only add to objects and functions that are explicitly defined in the code. Do not add any extra objects or functions.
     Add dependencies to objects and functions. Dependencies are other objects or functions that are used or called by the object or function, they don't
@@ -116,78 +395,601 @@ pub async fn request_llm(input : String) -> Result<String, Box<dyn std::error::E
         }
             Input :
             "#;
-    let input = format!("{}{}", base_prompt, input);
-    let body = serde_json::json!({
-        "model": "gpt-4.1-mini",
-        "input" : input
-    });
-    let res = client.post(response)
-        .header(AUTHORIZATION, format!("Bearer {}", api_key))
-        .header(CONTENT_TYPE, "application/json")
-        .header(ACCEPT, "application/json")
-        .json(&body)
-        .send()
-        .await?;
 
-    if !res.status().is_success() {
-        return Err(format!("Request failed with status: {}", res.status()).into());
-    }
-    else {
-        // println!("Request succeeded with status: {}", res.status());
-    }
+// Posts a request body to the OpenAI responses endpoint and returns the text of the first output message
+async fn post_responses(body: &serde_json::Value, config: &LlmConfig) -> Result<String, LlmError> {
+    let client = http_client(config)?;
+    let api_key = std::env::var("OPENAI_API_KEY")?;
+    let response = "https://api.openai.com/v1/responses";
+    let res = send_with_retry(|| {
+        client.post(response)
+            .header(AUTHORIZATION, format!("Bearer {}", api_key))
+            .header(CONTENT_TYPE, "application/json")
+            .header(ACCEPT, "application/json")
+            .json(body)
+    }, config).await?;
     let res_text = res.text().await?;
     // Convert the response to a JSON object
     let res_json: serde_json::Value = serde_json::from_str(&res_text)?;
-    // println!("Response JSON: {}", res_json);
-    // Extract the message field from the JSON object
-    let message = res_json.get("output");
-    if message.is_none() {
-        return Err("No message field in response".into());
-    }
-    let message = message.unwrap();
-    let text =  message[0]["content"][0]["text"].clone();
-    let text = text.as_str().unwrap().to_string();
-    // Extract only json from the text
+    response_text(&res_json)
+}
+
+// Pulls the text out of a responses API payload. Errors can come back with a 200 status, refusals are content parts
+// of their own and tool calls or reasoning items carry no text, so every output item is checked instead of
+// trusting output[0].content[0].
+pub fn response_text(res_json: &serde_json::Value) -> Result<String, LlmError> {
+    if let Some(error) = res_json.get("error").filter(|error| !error.is_null()) {
+        let message = error["message"].as_str().unwrap_or("no message");
+        return Err(format!("Responses API error: {}", message).into());
+    }
+    let output = res_json.get("output").and_then(|output| output.as_array()).ok_or("No output field in response")?;
+    let mut text = String::new();
+    let mut refusal = None;
+    for item in output.iter().filter(|item| item.get("type").is_none() || item["type"] == "message") {
+        for part in item["content"].as_array().into_iter().flatten() {
+            match part["type"].as_str() {
+                Some("output_text") | None => text.push_str(part["text"].as_str().unwrap_or("")),
+                Some("refusal") => refusal = Some(part["refusal"].as_str().unwrap_or("no reason given")),
+                Some(_) => {}
+            }
+        }
+    }
+    if !text.is_empty() {
+        return Ok(text);
+    }
+    if let Some(refusal) = refusal {
+        return Err(format!("Model refused the request: {}", refusal).into());
+    }
+    if res_json["status"] == "incomplete" {
+        let reason = res_json["incomplete_details"]["reason"].as_str().unwrap_or("unknown reason");
+        return Err(format!("Response is incomplete: {}", reason).into());
+    }
+    Err("Response contains no text output".into())
+}
+
+pub async fn request_llm(input : String, config: &LlmConfig) -> Result<String, LlmError> {
+    let input = format!("{}{}", config.prompt, input);
+    let mut body = serde_json::json!({
+        "model": config.model,
+        "input" : input
+    });
+    if config.structured_output {
+        body["text"] = serde_json::json!({
+            "format": {
+                "type": "json_schema",
+                "name": "canonicalization",
+                "strict": true,
+                "schema": canonicalization_schema(),
+            }
+        });
+    }
+    let text = post_responses(&body, config).await?;
+    if config.structured_output {
+        // The schema guarantees the whole text is the JSON document
+        return Ok(text);
+    }
+    extract_json(&text)
+}
+
+// Without structured output the model may wrap the JSON in prose or a code fence, keep only the outermost braces
+fn extract_json(text: &str) -> Result<String, LlmError> {
     let start = text.find('{').ok_or("No JSON found in response")?;
-    let end = text.rfind('}').ok_or("No JSON found in response")?;
-    let text = &text[start..=end];
-    let text = text.to_string();
-    Ok(text)
+    let end = text.rfind('}').filter(|&end| end > start).ok_or("No JSON found in response")?;
+    Ok(text[start..=end].to_string())
+}
 
+// JSON schema of the canonicalizer output, matching the example in the prompt. Strict mode requires every
+// property to be listed as required and no extra properties.
+fn canonicalization_schema() -> serde_json::Value {
+    let string_list = serde_json::json!({ "type": "array", "items": { "type": "string" } });
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "General Information": {
+                "type": "object",
+                "properties": {
+                    "Total Objects": { "type": "integer" },
+                    "Total Morphisms": { "type": "integer" },
+                    "Language": { "type": "string" },
+                    "Description": { "type": "string" },
+                },
+                "required": ["Total Objects", "Total Morphisms", "Language", "Description"],
+                "additionalProperties": false,
+            },
+            "Objects": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "type": { "type": "string" },
+                        "morphisms": string_list,
+                        "Dependencies": string_list,
+                        "description": { "type": "string" },
+                    },
+                    "required": ["name", "type", "morphisms", "Dependencies", "description"],
+                    "additionalProperties": false,
+                },
+            },
+            "Morphisms": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "type": { "type": "string" },
+                        "Dependencies": string_list,
+                        "description": { "type": "string" },
+                    },
+                    "required": ["name", "type", "Dependencies", "description"],
+                    "additionalProperties": false,
+                },
+            },
+        },
+        "required": ["General Information", "Objects", "Morphisms"],
+        "additionalProperties": false,
+    })
 }
 
 
+// Rough token count, OpenAI tokenizers average about four bytes of source per token
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+// A line starting at column zero usually opens a new top-level function, class or struct, so it is a safe
+// place to cut. Closing delimiters are excluded because they end the previous definition.
+fn is_top_level_start(line: &str) -> bool {
+    line.chars().next().is_some_and(|c| !c.is_whitespace() && !matches!(c, '}' | ')' | ']'))
+}
+
+// Splits source code into pieces of roughly `max_tokens` tokens each, cutting at the last top-level definition
+// before the budget runs out. Concatenating the pieces gives back the original input.
+pub fn split_source(input: &str, max_tokens: usize) -> Vec<String> {
+    let max_bytes = max_tokens.saturating_mul(4).max(1);
+    if input.len() <= max_bytes {
+        return vec![input.to_string()];
+    }
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    // Byte offset inside `current` of the most recent top-level definition
+    let mut boundary: Option<usize> = None;
+    for line in input.split_inclusive('\n') {
+        if is_top_level_start(line) {
+            boundary = Some(current.len());
+        }
+        if !current.is_empty() && current.len() + line.len() > max_bytes {
+            match boundary.filter(|&b| b > 0) {
+                Some(b) => {
+                    let rest = current.split_off(b);
+                    chunks.push(current);
+                    current = rest;
+                }
+                None => chunks.push(std::mem::take(&mut current)),
+            }
+            // The line being added now starts the new chunk and may itself be a boundary
+            boundary = if is_top_level_start(line) { Some(current.len()) } else { None };
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+// Splits source code into one piece per top-level definition, e.g. a function, a class with its methods or an impl
+// block. Lines without a body of their own, such as imports, attributes and comments, stay with the definition
+// after them. Pieces over `max_tokens` are split further like split_source. Concatenating the pieces gives back the
+// original input.
+pub fn split_symbols(input: &str, max_tokens: usize) -> Vec<String> {
+    let mut symbols = Vec::new();
+    let mut current = String::new();
+    // Whether `current` holds an indented line, i.e. a definition with a body
+    let mut has_body = false;
+    for line in input.split_inclusive('\n') {
+        if is_top_level_start(line) && has_body {
+            symbols.push(std::mem::take(&mut current));
+            has_body = false;
+        }
+        if !is_top_level_start(line) && !line.trim().is_empty() {
+            has_body = true;
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        symbols.push(current);
+    }
+    symbols.into_iter().flat_map(|symbol| split_source(&symbol, max_tokens)).collect()
+}
+
+// The pieces a file is canonicalized in, following config.chunk_mode
+pub fn chunk_source(input: &str, config: &LlmConfig) -> Vec<String> {
+    match config.chunk_mode {
+        ChunkMode::WholeFile => split_source(input, config.max_chunk_tokens),
+        ChunkMode::PerSymbol => split_symbols(input, config.max_chunk_tokens),
+    }
+}
+
+// Combines the canonicalizer output of several chunks of one file into a single response. Objects split across a
+// chunk boundary show up in both chunks, so they are merged by name, morphisms are kept as they are.
+pub fn merge_llm_responses(responses: &[String], file_path: &str) -> Result<String, ParseError> {
+    Ok(merge_parsed(responses, file_path)?.0.to_string())
+}
+
+// merge_llm_responses before serialization, along with the file description of every chunk. The merged description
+// is those joined in order, which reads well for consecutive parts of a file. In per-symbol mode request_llm_chunked
+// overrides it with the one describe_file asks for.
+fn merge_parsed(responses: &[String], file_path: &str) -> Result<(serde_json::Value, Vec<String>), ParseError> {
+    let mut language = String::new();
+    let mut descriptions: Vec<String> = Vec::new();
+    let mut objects: Vec<serde_json::Value> = Vec::new();
+    let mut morphisms: Vec<serde_json::Value> = Vec::new();
+    for response in responses {
+        let parsed: serde_json::Value = serde_json::from_str(response).map_err(|err| ParseError {
+            file_path: file_path.to_string(),
+            message: err.to_string(),
+        })?;
+        let general_info = &parsed["General Information"];
+        if language.is_empty() {
+            language = general_info["Language"].as_str().unwrap_or("").to_string();
+        }
+        if let Some(description) = general_info["Description"].as_str().filter(|d| !d.is_empty()) {
+            descriptions.push(description.to_string());
+        }
+        for obj in get_either_case(&parsed, "Objects").and_then(|v| v.as_array()).into_iter().flatten() {
+            let existing = objects.iter_mut().find(|o| o.get("name").is_some() && o.get("name") == obj.get("name"));
+            match existing {
+                Some(existing) => merge_object(existing, obj),
+                None => objects.push(obj.clone()),
+            }
+        }
+        if let Some(morphs) = get_either_case(&parsed, "Morphisms").and_then(|v| v.as_array()) {
+            morphisms.extend(morphs.iter().cloned());
+        }
+    }
+    let merged = serde_json::json!({
+        "General Information": {
+            "Total Objects": objects.len(),
+            "Total Morphisms": morphisms.len(),
+            "Language": language,
+            "Description": descriptions.join(" "),
+        },
+        "Objects": objects,
+        "Morphisms": morphisms,
+    });
+    Ok((merged, descriptions))
+}
+
+pub const FILE_DESCRIPTION_PROMPT: &str = "Below are descriptions of the definitions in one source file, each written without seeing the rest of the file. Write a single description of the whole file in one or two sentences: what it is for, not a list of its parts. Reply with the description only.\n\n";
+
+// Every per-symbol request describes the file from one definition only, so their descriptions are folded into one
+// by the LLM. When that fails the first description is kept, it usually comes from the top of the file.
+async fn describe_file(descriptions: &[String], provider: &dyn LlmProvider) -> String {
+    let Some(first) = descriptions.first() else {
+        return String::new();
+    };
+    if descriptions.len() == 1 {
+        return first.clone();
+    }
+    let listed: Vec<String> = descriptions.iter().map(|description| format!("- {}", description)).collect();
+    match provider.complete(format!("{}{}", FILE_DESCRIPTION_PROMPT, listed.join("\n"))).await {
+        Ok(description) if !description.trim().is_empty() => description.trim().to_string(),
+        Ok(_) => first.clone(),
+        Err(err) => {
+            log::warn!("File description request failed, keeping the description of the first definition: {}", err);
+            first.clone()
+        }
+    }
+}
+
+// Models do not reliably keep the casing of the example output, so keys are looked up as written and with the
+// case of their first letter flipped ("Dependencies" and "dependencies")
+fn key_variants(key: &str) -> [String; 2] {
+    let mut chars = key.chars();
+    let flipped = match chars.next() {
+        Some(first) if first.is_uppercase() => first.to_lowercase().chain(chars).collect(),
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    };
+    [key.to_string(), flipped]
+}
+
+fn get_either_case<'a>(value: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    key_variants(key).iter().find_map(|variant| value.get(variant))
+}
+
+fn string_array(value: &serde_json::Value, key: &str) -> Vec<String> {
+    get_either_case(value, key)
+        .and_then(|v| v.as_array())
+        .map(|arr| arr.iter().filter_map(|item| item.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default()
+}
+
+// Folds the morphism and dependency lists of a duplicate object into the first occurrence
+fn merge_object(existing: &mut serde_json::Value, duplicate: &serde_json::Value) {
+    for key in ["morphisms", "Dependencies"] {
+        let Some(extra) = get_either_case(duplicate, key).and_then(|v| v.as_array()) else {
+            continue;
+        };
+        let Some(map) = existing.as_object_mut() else {
+            return;
+        };
+        // Extend whichever casing the first occurrence used
+        let key = key_variants(key).into_iter().find(|variant| map.contains_key(variant)).unwrap_or_else(|| key.to_string());
+        let list = map.entry(key).or_insert_with(|| serde_json::Value::Array(Vec::new()));
+        if let Some(list) = list.as_array_mut() {
+            for item in extra {
+                if !list.contains(item) {
+                    list.push(item.clone());
+                }
+            }
+        }
+    }
+}
+
+// Canonicalizes a whole file through `provider`, one request per chunk from chunk_source. The responses are merged
+// back into one for the file, in per-symbol mode with one more request for the file description.
+pub async fn request_llm_chunked(input: String, file_path: &str, provider: &dyn LlmProvider, config: &LlmConfig) -> Result<String, LlmError> {
+    let chunks = chunk_source(&input, config);
+    if chunks.len() == 1 {
+        return provider.canonicalize(input).await;
+    }
+    let mut responses = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        responses.push(provider.canonicalize(chunk).await?);
+    }
+    let (mut merged, descriptions) = merge_parsed(&responses, file_path)?;
+    if config.chunk_mode == ChunkMode::PerSymbol {
+        merged["General Information"]["Description"] = describe_file(&descriptions, provider).await.into();
+    }
+    Ok(merged.to_string())
+}
+
+
+pub const EXPAND_PROMPT: &str = "You help search a codebase. Rewrite the search query below into one or two sentences describing the code being looked for, mentioning related identifiers, synonyms and technical terms. For example \"send http\" could mention request, client, fetch and GET. Reply with the rewritten query only.\n\nQuery: ";
+
+// Short queries embed poorly, so the model turns them into a richer description first. The original query is kept
+// in front of the expansion, and returned unchanged when the extra call fails.
+pub async fn expand_query(query: &str, provider: &dyn LlmProvider) -> String {
+    match provider.complete(format!("{}{}", EXPAND_PROMPT, query)).await {
+        Ok(expansion) if !expansion.trim().is_empty() => format!("{} {}", query, expansion.trim()),
+        Ok(_) => query.to_string(),
+        Err(err) => {
+            log::warn!("Query expansion failed, searching for '{}' as typed: {}", query, err);
+            query.to_string()
+        }
+    }
+}
+
+pub const SUMMARY_PROMPT: &str = "Below is every file of a codebase with its language and a description of what it contains. Write a summary of the codebase for a developer seeing it for the first time: what it does, its main components and how they fit together, and the languages used. Use at most three short paragraphs and reply with the summary only.\n\n";
+
+// Prompts are kept to this many bytes of overview, the remaining files are only counted so huge repositories still
+// fit in the context window
+pub const MAX_SUMMARY_OVERVIEW_BYTES: usize = 200_000;
+
+// Condenses the per file overview built by code_base::file_overview into a repository summary
+pub async fn summarize_overview(overview: &str, provider: &dyn LlmProvider) -> Result<String, LlmError> {
+    let mut prompt = SUMMARY_PROMPT.to_string();
+    let mut omitted = 0;
+    for line in overview.lines() {
+        if prompt.len() + line.len() > SUMMARY_PROMPT.len() + MAX_SUMMARY_OVERVIEW_BYTES {
+            omitted += 1;
+            continue;
+        }
+        prompt.push_str(line);
+        prompt.push('\n');
+    }
+    if omitted > 0 {
+        prompt.push_str(&format!("... and {} more files\n", omitted));
+    }
+    let summary = provider.complete(prompt).await?;
+    if summary.trim().is_empty() {
+        return Err("Empty summary in response".into());
+    }
+    Ok(summary.trim().to_string())
+}
+
+pub const RERANK_PROMPT: &str = "You rank search results for a codebase. Order the numbered candidates below by how well they match the search query, most relevant first. Reply with a JSON array of the candidate numbers only, for example [2, 0, 1].\n\n";
+
+// Asks the LLM to order `candidates`, short "name: description" texts, by relevance to the query. The answer is
+// always a permutation of 0..candidates.len(): numbers that are out of range or repeated are dropped, and
+// candidates the model left out follow in their original order.
+pub async fn rerank_order(query: &str, candidates: &[String], provider: &dyn LlmProvider) -> Result<Vec<usize>, LlmError> {
+    let mut prompt = format!("{}Query: {}\n\nCandidates:\n", RERANK_PROMPT, query);
+    for (i, candidate) in candidates.iter().enumerate() {
+        prompt.push_str(&format!("[{}] {}\n", i, candidate.replace('\n', " ")));
+    }
+    let answer = provider.complete(prompt).await?;
+    let start = answer.find('[').ok_or("No JSON array in rerank response")?;
+    let end = answer.rfind(']').ok_or("No JSON array in rerank response")?;
+    let ranked: Vec<usize> = serde_json::from_str(&answer[start..=end])?;
+    let mut order: Vec<usize> = Vec::with_capacity(candidates.len());
+    for i in ranked.into_iter().chain(0..candidates.len()) {
+        if i < candidates.len() && !order.contains(&i) {
+            order.push(i);
+        }
+    }
+    Ok(order)
+}
+
 // Function that outputs embeddings for a given text using openAI embeddings endpoint
-pub async fn get_embeddings(text: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+pub async fn get_embeddings(text: String, config: &LlmConfig) -> Result<Vec<f32>, LlmError> {
+    let mut embeddings = get_embeddings_batch(vec![text], config).await?;
+    embeddings.pop().ok_or_else(|| "No embedding found".into())
+}
+
+// Most inputs the embeddings endpoint takes in one request
+pub const MAX_EMBEDDING_BATCH: usize = 2048;
+
+// Embeds several texts with as few requests as possible, the returned vectors are in the same order as `texts`.
+// Empty texts are rejected by the endpoint, so they are not sent and get an empty vector back. Longer lists are
+// sent MAX_EMBEDDING_BATCH at a time.
+pub async fn get_embeddings_batch(texts: Vec<String>, config: &LlmConfig) -> Result<Vec<Vec<f32>>, LlmError> {
+    let mut embeddings: Vec<Vec<f32>> = vec![Vec::new(); texts.len()];
+    let (positions, inputs): (Vec<usize>, Vec<String>) = texts
+        .into_iter()
+        .enumerate()
+        .filter(|(_, text)| !text.is_empty())
+        .unzip();
+    if inputs.is_empty() {
+        return Ok(embeddings);
+    }
+    let client = http_client(config)?;
     let api_key = std::env::var("OPENAI_API_KEY")?;
+    for (positions, inputs) in positions.chunks(MAX_EMBEDDING_BATCH).zip(inputs.chunks(MAX_EMBEDDING_BATCH)) {
+        let fetched = request_embeddings(&client, &api_key, inputs, config).await?;
+        for (&position, embedding) in positions.iter().zip(fetched) {
+            embeddings[position] = embedding;
+        }
+    }
+    Ok(embeddings)
+}
+
+// One request to the embeddings endpoint, the vectors come back in the order of `inputs`
+async fn request_embeddings(client: &reqwest::Client, api_key: &str, inputs: &[String], config: &LlmConfig) -> Result<Vec<Vec<f32>>, LlmError> {
     let response = "https://api.openai.com/v1/embeddings";
     let body = serde_json::json!({
-        "model": "text-embedding-3-large",
-        "input" : text
+        "model": config.embedding_model,
+        "input" : inputs
     });
-    let res = client.post(response)
-        .header(AUTHORIZATION, format!("Bearer {}", api_key))
-        .header(CONTENT_TYPE, "application/json")
-        .json(&body)
-        .send()
-        .await?
+    let res = send_with_retry(|| {
+        client.post(response)
+            .header(AUTHORIZATION, format!("Bearer {}", api_key))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&body)
+    }, config).await?
         .json::<serde_json::Value>()
         .await?;
 
-    let embeddings = res["data"][0]["embedding"]
-        .as_array()
-        .ok_or("No embedding found")?
-        .iter()
-        .filter_map(|v| v.as_f64().map(|f| f as f32))
-        .collect();
+    if let Some(message) = res["error"]["message"].as_str() {
+        return Err(format!("Embeddings API error: {}", message).into());
+    }
+    let data = res["data"].as_array().ok_or("No embedding found")?;
+    if data.len() != inputs.len() {
+        return Err(format!("Expected {} embeddings, got {}", inputs.len(), data.len()).into());
+    }
+    let mut embeddings: Vec<Vec<f32>> = vec![Vec::new(); inputs.len()];
+    // The endpoint tags every embedding with the index of its input, place them by that rather than trusting the order
+    for (order, item) in data.iter().enumerate() {
+        let index = item["index"].as_u64().map(|i| i as usize).unwrap_or(order);
+        let embedding = item["embedding"]
+            .as_array()
+            .ok_or("No embedding found")?
+            .iter()
+            .filter_map(|v| v.as_f64().map(|f| f as f32))
+            .collect();
+        *embeddings.get_mut(index).ok_or("Embedding index out of range")? = embedding;
+    }
 
     Ok(embeddings)
 }
 
 
-// Function that takes in raw llm response and parses it into File, Object and Morphism structs
-pub fn parse_llm_response(response: String, file_path: String) -> (File, Vec<Object>, Vec<Morphism>) {
+// Keywords that introduce a definition in the languages we commonly index
+const DEFINITION_KEYWORDS: &[&str] = &[
+    "fn", "def", "class", "struct", "enum", "trait", "interface", "impl", "type", "func", "function", "union",
+];
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// True when `name` appears in `line` as a whole identifier right after a definition keyword
+fn defines_symbol(line: &str, name: &str) -> bool {
+    let mut previous_word: Option<&str> = None;
+    for word in line.split(|c: char| !is_identifier_char(c)).filter(|w| !w.is_empty()) {
+        if word == name && previous_word.is_some_and(|p| DEFINITION_KEYWORDS.contains(&p)) {
+            return true;
+        }
+        previous_word = Some(word);
+    }
+    false
+}
+
+// Last line of the definition starting at `start` (0-based). Brace languages are followed until the braces
+// balance, otherwise the block continues while lines are blank or indented deeper than the first line.
+fn definition_end(lines: &[&str], start: usize) -> usize {
+    let mut depth: i64 = 0;
+    let mut seen_brace = false;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        for c in line.chars() {
+            match c {
+                '{' => {
+                    depth += 1;
+                    seen_brace = true;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        if seen_brace && depth <= 0 {
+            return i;
+        }
+        // No brace on the opening line (or a ';' terminated declaration) means this is not a braced block
+        if !seen_brace && (i > start || line.trim_end().ends_with(';')) {
+            break;
+        }
+    }
+    if seen_brace {
+        return lines.len().saturating_sub(1);
+    }
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let base_indent = indent(lines[start]);
+    let mut end = start;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if indent(line) <= base_indent {
+            break;
+        }
+        end = i;
+    }
+    end
+}
+
+// Finds the definition of `name` in the source, skipping definitions already claimed by an earlier symbol of the
+// same name (e.g. two classes that both define `speak`). Returns 1-based inclusive lines.
+fn locate_symbol(lines: &[&str], name: &str, claimed: &[usize]) -> Option<(usize, usize)> {
+    if name.is_empty() {
+        return None;
+    }
+    let start = lines
+        .iter()
+        .enumerate()
+        .position(|(i, line)| !claimed.contains(&(i + 1)) && defines_symbol(line, name))?;
+    Some((start + 1, definition_end(lines, start) + 1))
+}
+
+// Fills in start_line/end_line for every object and morphism by searching the source for its definition
+pub fn locate_symbols(source: &str, objects: &mut [Object], morphisms: &mut [Morphism]) {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut claimed: Vec<usize> = Vec::new();
+    for obj in objects.iter_mut() {
+        if let Some((start, end)) = locate_symbol(&lines, &obj.name, &claimed) {
+            obj.start_line = start;
+            obj.end_line = end;
+            claimed.push(start);
+        }
+    }
+    for morph in morphisms.iter_mut() {
+        if let Some((start, end)) = locate_symbol(&lines, &morph.name, &claimed) {
+            morph.start_line = start;
+            morph.end_line = end;
+            claimed.push(start);
+        }
+    }
+}
+
+// Empty descriptions are never sent to the embeddings endpoint, so only a described record can have failed
+pub fn embedding_missing(description: &str, embedding: &[f32]) -> bool {
+    !description.is_empty() && embedding.is_empty()
+}
+
+// Function that takes in raw llm response and parses it into File, Object and Morphism structs.
+// `source` is the file content sent to the LLM, used to locate each symbol.
+pub fn parse_llm_response(response: String, file_path: String, source: &str, provider: &dyn LlmProvider) -> Result<ParsedFile, ParseError> {
     // Extract File Name
     let file_name = std::path::Path::new(&file_path)
         .file_name()
@@ -197,77 +999,373 @@ pub fn parse_llm_response(response: String, file_path: String) -> (File, Vec<Obj
 
 
     // Parse the output string as JSON
-    let parsed: serde_json::Value = serde_json::from_str(&response).expect("Failed to parse JSON");
+    let parsed: serde_json::Value = match serde_json::from_str(&response) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            return Err(ParseError {
+                file_path,
+                message: err.to_string(),
+            });
+        }
+    };
     // Extract general information
     let general_info = &parsed["General Information"];
-    let language = general_info["Language"].as_str().unwrap_or("").to_string();
+    let llm_language = general_info["Language"].as_str().unwrap_or("");
+    // The extension is more reliable than the model, its guess is only used for unknown extensions
+    let language = match crate::indexer::detect_language(&file_path) {
+        Some(detected) => {
+            if !llm_language.is_empty() && !llm_language.eq_ignore_ascii_case(&detected) {
+                log::info!("Language mismatch for {}: extension says {}, LLM says {}", file_path, detected, llm_language);
+            }
+            detected
+        }
+        None => llm_language.to_string(),
+    };
     let description = general_info["Description"].as_str().unwrap_or("").to_string();
-    // Get embedding for description
-    let description_clone = description.clone();
-    let embeddings = get_embeddings(description_clone);
-    let description_embedding = tokio::runtime::Runtime::new().unwrap().block_on(embeddings).unwrap_or_else(|_| vec![]);
-    // Create File struct
-    let file = File {
-        size: 0, // Size can be set later
+    // Create File struct, embeddings are filled in below with a single batched request
+    let mut file = File {
+        // The source was read from this file in full, so its length is the size on disk
+        size: source.len() as u64,
         name: file_name,
         path: file_path,
         language,
-        embedding: description_embedding,
+        embedding: Vec::new(),
+        embedding_failed: false,
         description,
 
     };
 
     // Extract Objects
     let mut objects = Vec::new();
-    if let Some(objs) = parsed.get("Objects").and_then(|v| v.as_array()) {
+    if let Some(objs) = get_either_case(&parsed, "Objects").and_then(|v| v.as_array()) {
         for obj in objs {
             let name = obj.get("name").and_then(|v| v.as_str()).unwrap_or("").to_string();
             let obj_type = obj.get("type").and_then(|v| v.as_str()).unwrap_or("").to_string();
-            let morphisms = obj.get("morphisms")
-                .and_then(|v| v.as_array())
-                .map(|arr| arr.iter().filter_map(|m| m.as_str().map(|s| s.to_string())).collect())
-                .unwrap_or_else(Vec::new);
-            let dependencies = obj.get("Dependencies")
-                .and_then(|v| v.as_array())
-                .map(|arr| arr.iter().filter_map(|d| d.as_str().map(|s| s.to_string())).collect())
-                .unwrap_or_else(Vec::new);
+            let morphisms = string_array(obj, "morphisms");
+            let dependencies = string_array(obj, "Dependencies");
             let description = obj.get("description").and_then(|v| v.as_str()).unwrap_or("").to_string();
-            let embeddings = get_embeddings(description.clone());
-            let embeddings = tokio::runtime::Runtime::new().unwrap().block_on(embeddings).unwrap_or_else(|_| vec![]);
 
             objects.push(Object {
                 name,
                 obj_type,
                 morphisms,
                 dependencies,
-                embedding: embeddings,
+                embedding: Vec::new(),
+                embedding_failed: false,
                 description,
+                file_path: file.path.clone(),
+                start_line: 0,
+                end_line: 0,
+                duplicate_file_paths: Vec::new(),
             });
         }
     }
 
     // Extract Morphisms
     let mut morphisms = Vec::new();
-    if let Some(morphs) = parsed.get("Morphisms").and_then(|v| v.as_array()) {
+    if let Some(morphs) = get_either_case(&parsed, "Morphisms").and_then(|v| v.as_array()) {
         for morph in morphs {
             let name = morph.get("name").and_then(|v| v.as_str()).unwrap_or("").to_string();
             let morph_type = morph.get("type").and_then(|v| v.as_str()).unwrap_or("").to_string();
-            let dependencies = morph.get("Dependencies")
-                .and_then(|v| v.as_array())
-                .map(|arr| arr.iter().filter_map(|d| d.as_str().map(|s| s.to_string())).collect())
-                .unwrap_or_else(Vec::new);
+            let dependencies = string_array(morph, "Dependencies");
             let description = morph.get("description").and_then(|v| v.as_str()).unwrap_or("").to_string();
-            let embeddings = get_embeddings(description.clone());
-            let embeddings = tokio::runtime::Runtime::new().unwrap().block_on(embeddings).unwrap_or_else(|_| vec![]);
             morphisms.push(Morphism {
                 name,
                 morph_type,
                 dependencies,
-                embedding: embeddings,
+                embedding: Vec::new(),
+                embedding_failed: false,
                 description,
+                file_path: file.path.clone(),
+                start_line: 0,
+                end_line: 0,
+                duplicate_file_paths: Vec::new(),
+                owner_object: None,
             });
         }
     }
 
-    (file, objects, morphisms)
+    locate_symbols(source, &mut objects, &mut morphisms);
+
+    // Embed the file, object and morphism descriptions in one round trip, in that order
+    let mut descriptions = vec![file.description.clone()];
+    descriptions.extend(objects.iter().map(|obj| obj.description.clone()));
+    descriptions.extend(morphisms.iter().map(|morph| morph.description.clone()));
+    // A failed batch leaves every record flagged, code_base::retry_failed_embeddings gets another go at them
+    let embeddings = runtime().block_on(provider.embed_batch(descriptions));
+    if let Err(err) = &embeddings {
+        log::warn!("Failed to embed descriptions of {}: {}", file.path, err);
+    }
+    let mut embeddings = embeddings.unwrap_or_default().into_iter();
+    file.embedding = embeddings.next().unwrap_or_default();
+    file.embedding_failed = embedding_missing(&file.description, &file.embedding);
+    for obj in objects.iter_mut() {
+        obj.embedding = embeddings.next().unwrap_or_default();
+        obj.embedding_failed = embedding_missing(&obj.description, &obj.embedding);
+    }
+    for morph in morphisms.iter_mut() {
+        morph.embedding = embeddings.next().unwrap_or_default();
+        morph.embedding_failed = embedding_missing(&morph.description, &morph.embedding);
+    }
+
+    Ok((file, objects, morphisms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let config = LlmConfig { initial_backoff_ms: 100, max_backoff_ms: 1_000, ..Default::default() };
+        let delays: Vec<u64> = (1..=6).map(|attempt| backoff_delay(&config, attempt).as_millis() as u64).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1_000, 1_000]);
+        // Far past the cap the shift must not overflow
+        assert_eq!(backoff_delay(&config, 100).as_millis(), 1_000);
+        assert_eq!(parse_retry_after("2", &config), Some(std::time::Duration::from_secs(1)));
+        assert_eq!(parse_retry_after(" 0 ", &config), Some(std::time::Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", &config), None);
+    }
+
+    #[test]
+    fn only_rate_limits_and_server_errors_are_retried() {
+        for status in [429, 500, 502, 503] {
+            assert!(is_retryable_status(reqwest::StatusCode::from_u16(status).unwrap()), "{}", status);
+        }
+        for status in [400, 401, 403, 404, 422] {
+            assert!(!is_retryable_status(reqwest::StatusCode::from_u16(status).unwrap()), "{}", status);
+        }
+    }
+
+    // Every embedding request fails, parsing still completes with flagged records
+    struct NoEmbeddings;
+
+    #[async_trait::async_trait]
+    impl LlmProvider for NoEmbeddings {
+        async fn canonicalize(&self, _code: String) -> Result<String, LlmError> {
+            Err("not used".into())
+        }
+
+        async fn embed(&self, _text: String) -> Result<Vec<f32>, LlmError> {
+            Err("no embeddings in tests".into())
+        }
+    }
+
+    struct Rewriter;
+
+    #[async_trait::async_trait]
+    impl LlmProvider for Rewriter {
+        async fn canonicalize(&self, _code: String) -> Result<String, LlmError> {
+            Err("not used".into())
+        }
+
+        async fn embed(&self, _text: String) -> Result<Vec<f32>, LlmError> {
+            Err("not used".into())
+        }
+
+        async fn complete(&self, prompt: String) -> Result<String, LlmError> {
+            assert!(prompt.starts_with(EXPAND_PROMPT));
+            Ok(" Sends an HTTP request with a client, fetch or GET.\n".to_string())
+        }
+    }
+
+    #[test]
+    fn extract_json_keeps_the_outermost_braces() {
+        assert_eq!(extract_json("Here it is: {\"a\": {}} done").unwrap(), "{\"a\": {}}");
+        assert!(extract_json("no json").is_err());
+        assert!(extract_json("} before {").is_err());
+    }
+
+    #[test]
+    fn expand_query_appends_the_rewrite_or_falls_back() {
+        let expanded = runtime().block_on(expand_query("send http", &Rewriter));
+        assert_eq!(expanded, "send http Sends an HTTP request with a client, fetch or GET.");
+        assert_eq!(runtime().block_on(expand_query("send http", &NoEmbeddings)), "send http");
+    }
+
+    #[test]
+    fn response_text_reports_refusals_errors_and_skips_other_parts() {
+        let refusal = serde_json::json!({
+            "status": "completed",
+            "error": null,
+            "output": [{"type": "message", "content": [{"type": "refusal", "refusal": "I can't help with that."}]}]
+        });
+        let err = response_text(&refusal).unwrap_err().to_string();
+        assert_eq!(err, "Model refused the request: I can't help with that.");
+
+        let error = serde_json::json!({"error": {"message": "Rate limit reached", "type": "rate_limit"}, "output": []});
+        assert_eq!(response_text(&error).unwrap_err().to_string(), "Responses API error: Rate limit reached");
+        assert!(response_text(&serde_json::json!({"output": []})).is_err());
+
+        let mixed = serde_json::json!({
+            "output": [
+                {"type": "reasoning", "summary": []},
+                {"type": "function_call", "name": "lookup", "arguments": "{}"},
+                {"type": "message", "content": [{"type": "output_text", "text": "{\"Objects\": []}"}]}
+            ]
+        });
+        assert_eq!(response_text(&mixed).unwrap(), "{\"Objects\": []}");
+    }
+
+    #[test]
+    fn parse_llm_response_accepts_either_key_casing() {
+        let capitalized = r#"{"General Information": {"Language": "Python", "Description": "Animals."},
+            "Objects": [{"name": "Dog", "type": "class", "Morphisms": ["speak"], "Dependencies": ["Animal"], "description": "A dog."}],
+            "Morphisms": [{"name": "speak", "type": "method", "Dependencies": ["self"], "description": "Barks."}]}"#;
+        let lowercase = r#"{"General Information": {"Language": "Python", "Description": "Animals."},
+            "objects": [{"name": "Dog", "type": "class", "morphisms": ["speak"], "dependencies": ["Animal"], "description": "A dog."}],
+            "morphisms": [{"name": "speak", "type": "method", "dependencies": ["self"], "description": "Barks."}]}"#;
+        let source = "class Dog(Animal):\n    def speak(self):\n        return 'Woof'\n";
+        let parse = |response: &str| parse_llm_response(response.to_string(), "dog.py".to_string(), source, &NoEmbeddings).unwrap();
+        let (file, objects, morphisms) = parse(capitalized);
+        assert_eq!(file.size, source.len() as u64);
+        assert_eq!(objects[0].dependencies, vec!["Animal"]);
+        assert_eq!(objects[0].morphisms, vec!["speak"]);
+        assert_eq!(morphisms[0].dependencies, vec!["self"]);
+        assert_eq!(format!("{:?}", parse(capitalized)), format!("{:?}", parse(lowercase)));
+    }
+
+    #[test]
+    fn stalled_requests_time_out_and_are_retried() {
+        // Accepts connections but never answers them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let config = LlmConfig { request_timeout_secs: 1, max_attempts: 2, initial_backoff_ms: 10, ..Default::default() };
+        let client = http_client(&config).unwrap();
+        let err = runtime().block_on(send_with_retry(|| client.get(url.as_str()), &config)).unwrap_err();
+        assert!(err.to_string().contains("after 2 attempt(s)"), "{}", err);
+        drop(listener);
+    }
+
+    // Answers every connection with `status_line` and the extra headers, returns the URL and how many were served
+    fn canned_server(status_line: &'static str, headers: &'static str) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let served = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = std::sync::Arc::clone(&served);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request);
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", status_line, headers);
+            }
+        });
+        (url, served)
+    }
+
+    #[test]
+    fn client_errors_fail_without_retrying() {
+        let (url, served) = canned_server("404 Not Found", "");
+        let config = LlmConfig { max_attempts: 3, initial_backoff_ms: 10, ..Default::default() };
+        let client = http_client(&config).unwrap();
+        let err = runtime().block_on(send_with_retry(|| client.get(url.as_str()), &config)).unwrap_err();
+        assert!(err.to_string().contains("404 Not Found after 1 attempt(s)"), "{}", err);
+        assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retry_after_is_capped_by_max_backoff() {
+        let (url, served) = canned_server("503 Service Unavailable", "Retry-After: 3600\r\n");
+        let config = LlmConfig { max_attempts: 2, initial_backoff_ms: 10, max_backoff_ms: 50, ..Default::default() };
+        let client = http_client(&config).unwrap();
+        let started = std::time::Instant::now();
+        let err = runtime().block_on(send_with_retry(|| client.get(url.as_str()), &config)).unwrap_err();
+        assert!(err.to_string().contains("after 2 attempt(s)"), "{}", err);
+        assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn rerank_order_is_always_a_permutation() {
+        let candidates: Vec<String> = ["a: first", "b: second", "c: third", "d: fourth"].iter().map(|c| c.to_string()).collect();
+        let provider = crate::mock::MockProvider::new().with_completion("Here you go: [2, 7, 0, 2]");
+        assert_eq!(runtime().block_on(rerank_order("query", &candidates, &provider)).unwrap(), vec![2, 0, 1, 3]);
+        let rambling = crate::mock::MockProvider::new().with_completion("The best match is c.");
+        assert!(runtime().block_on(rerank_order("query", &candidates, &rambling)).is_err());
+    }
+
+    #[test]
+    fn split_source_cuts_at_top_level_definitions() {
+        let input = "fn a() {\n    1\n}\nfn b() {\n    2\n}\nfn c() {\n    3\n}\n";
+        // Each function is 17 bytes, a budget of 9 tokens (36 bytes) fits two of them but not three
+        let chunks = split_source(input, 9);
+        assert_eq!(chunks, vec!["fn a() {\n    1\n}\nfn b() {\n    2\n}\n", "fn c() {\n    3\n}\n"]);
+        assert_eq!(chunks.concat(), input);
+        assert_eq!(split_source(input, 1000), vec![input.to_string()]);
+    }
+
+    #[test]
+    fn split_symbols_gives_each_definition_its_own_piece() {
+        let input = "import os\nimport sys\n\n@cached\ndef a():\n    return 1\n\nclass B:\n    def c(self):\n        pass\nX = 1\n";
+        let symbols = split_symbols(input, 1000);
+        assert_eq!(symbols, vec!["import os\nimport sys\n\n@cached\ndef a():\n    return 1\n\n", "class B:\n    def c(self):\n        pass\n", "X = 1\n"]);
+        assert_eq!(symbols.concat(), input);
+
+        let rust = "use std::fmt;\n\nstruct A {\n    x: u8,\n}\n\nimpl A {\n    fn new() -> Self {\n        A { x: 0 }\n    }\n}\n";
+        assert_eq!(split_symbols(rust, 1000), vec!["use std::fmt;\n\nstruct A {\n    x: u8,\n}\n\n", "impl A {\n    fn new() -> Self {\n        A { x: 0 }\n    }\n}\n"]);
+    }
+
+    #[test]
+    fn per_symbol_mode_canonicalizes_every_definition() {
+        let response = |name: &str| format!(r#"{{"General Information": {{"Language": "Python", "Description": "Defines {n}."}}, "Objects": [], "Morphisms": [{{"name": "{n}", "type": "function", "Dependencies": [], "description": "The {n} function."}}]}}"#, n = name);
+        let provider = crate::mock::MockProvider::new().with_response("def first", &response("first")).with_response("def second", &response("second"));
+        let input = "def first():\n    pass\n\ndef second():\n    pass\n".to_string();
+        let config = LlmConfig { chunk_mode: ChunkMode::PerSymbol, ..Default::default() };
+        let merged = runtime().block_on(request_llm_chunked(input.clone(), "two.py", &provider, &config)).unwrap();
+        assert_eq!(provider.canonicalize_calls(), 2);
+        let merged: serde_json::Value = serde_json::from_str(&merged).unwrap();
+        // Without completions the first definition describes the file
+        assert_eq!(merged["General Information"]["Description"], "Defines first.");
+        assert_eq!(merged["Morphisms"].as_array().unwrap().len(), 2);
+
+        let provider = provider.with_completion(" Defines first and second. ");
+        let merged = runtime().block_on(request_llm_chunked(input, "two.py", &provider, &config)).unwrap();
+        let merged: serde_json::Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(merged["General Information"]["Description"], "Defines first and second.");
+    }
+
+    #[test]
+    fn merge_llm_responses_dedups_objects_by_name() {
+        let first = r#"{"General Information": {"Language": "Rust", "Description": "First half."},
+            "Objects": [{"name": "Parser", "type": "struct", "morphisms": ["new"], "Dependencies": ["Lexer"], "description": "Parses."}],
+            "Morphisms": [{"name": "new", "type": "method", "Dependencies": [], "description": "Builds a parser."}]}"#;
+        let second = r#"{"General Information": {"Language": "Rust", "Description": "Second half."},
+            "Objects": [{"name": "Parser", "type": "struct", "morphisms": ["parse"], "Dependencies": ["Lexer", "Token"], "description": "Parses."}],
+            "Morphisms": [{"name": "parse", "type": "method", "Dependencies": ["Token"], "description": "Parses tokens."}]}"#;
+        let merged = merge_llm_responses(&[first.to_string(), second.to_string()], "parser.rs").unwrap();
+        let merged: serde_json::Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(merged["General Information"]["Description"], "First half. Second half.");
+        let objects = merged["Objects"].as_array().unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0]["morphisms"], serde_json::json!(["new", "parse"]));
+        assert_eq!(objects[0]["Dependencies"], serde_json::json!(["Lexer", "Token"]));
+        assert_eq!(merged["Morphisms"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn locate_symbols_finds_braced_and_indented_definitions() {
+        let source = "import json\nclass Animal:\n    def speak(self):\n        pass\n\nclass Dog(Animal):\n    def speak(self):\n        return 'Woof'\n";
+        let mut objects = vec![
+            Object { name: "Animal".to_string(), ..Default::default() },
+            Object { name: "Dog".to_string(), ..Default::default() },
+        ];
+        let mut morphisms = vec![
+            Morphism { name: "speak".to_string(), ..Default::default() },
+            Morphism { name: "speak".to_string(), ..Default::default() },
+        ];
+        locate_symbols(source, &mut objects, &mut morphisms);
+        assert_eq!((objects[0].start_line, objects[0].end_line), (2, 4));
+        assert_eq!((objects[1].start_line, objects[1].end_line), (6, 8));
+        assert_eq!((morphisms[0].start_line, morphisms[0].end_line), (3, 4));
+        assert_eq!((morphisms[1].start_line, morphisms[1].end_line), (7, 8));
+
+        let source = "pub struct Point {\n    x: i32,\n}\n\nfn origin() -> Point {\n    Point { x: 0 }\n}\n";
+        let mut objects = vec![Object { name: "Point".to_string(), ..Default::default() }];
+        let mut morphisms = vec![Morphism { name: "origin".to_string(), ..Default::default() }];
+        locate_symbols(source, &mut objects, &mut morphisms);
+        assert_eq!((objects[0].start_line, objects[0].end_line), (1, 3));
+        assert_eq!((morphisms[0].start_line, morphisms[0].end_line), (5, 7));
+    }
 }
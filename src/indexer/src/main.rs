@@ -1,82 +1,551 @@
-mod indexer;
-mod llm;
-use std::thread;
-use std::sync;
+use clap::{Parser, Subcommand, ValueEnum};
+use indexer::{cache, checkpoint, indexer as eve, llm, store, watch};
+
+#[derive(Parser)]
+#[command(name = "eve", about = "Canonicalize a codebase with an LLM and search it semantically")]
+struct Cli {
+    /// Backend used to canonicalize source files
+    #[arg(long, global = true, value_enum, default_value_t = ProviderKind::Openai)]
+    provider: ProviderKind,
+    /// Model used to canonicalize source files, defaults to a model of the chosen provider
+    #[arg(long, global = true)]
+    model: Option<String>,
+    /// Maximum number of LLM requests in flight at once
+    #[arg(long, global = true, default_value_t = 8)]
+    max_concurrent_requests: usize,
+    /// Seconds before a stalled provider request is abandoned and retried, 0 waits forever
+    #[arg(long, global = true, default_value_t = 60)]
+    request_timeout_secs: u64,
+    /// Send whole files to the LLM, or every top-level definition on its own for finer grained results
+    #[arg(long, global = true, value_enum, default_value_t = ChunkMode::WholeFile)]
+    chunk_mode: ChunkMode,
+    /// Files larger than this many bytes are skipped
+    #[arg(long, global = true, default_value_t = 1_000_000)]
+    max_file_bytes: u64,
+    /// Collapse objects and morphisms with the same name whose embeddings are at least this similar
+    #[arg(long, global = true)]
+    dedup_threshold: Option<f32>,
+    /// Always call the provider instead of reusing cached responses and embeddings
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Directory holding cached provider responses
+    #[arg(long, global = true, default_value = indexer::cache::DEFAULT_CACHE_DIR)]
+    cache_dir: String,
+    /// File holding the canonicalization instructions sent ahead of every source file, replaces the built-in prompt
+    #[arg(long, global = true)]
+    prompt_file: Option<String>,
+    /// Save progress while indexing, so an interrupted run started again resumes from it. Every save rewrites the
+    /// whole index, embeddings included, so leave it off for small repositories.
+    #[arg(long, global = true)]
+    checkpoint: bool,
+    /// Where checkpoints are saved, implies --checkpoint. Defaults to a file in the working directory named after
+    /// the indexed root.
+    #[arg(long, global = true)]
+    checkpoint_file: Option<String>,
+    /// Files indexed between two checkpoints, 0 only saves one when interrupted with Ctrl-C
+    #[arg(long, global = true, default_value_t = checkpoint::DEFAULT_CHECKPOINT_EVERY)]
+    checkpoint_every: usize,
+    /// List the files that would be indexed and exit without calling any provider
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Leave out files matched by .gitignore rules, including nested .gitignore files
+    #[arg(long, global = true)]
+    gitignore: bool,
+    /// Ask the LLM for a summary of the whole codebase after indexing, costs one extra request. Without it the
+    /// summary lists the description of every file
+    #[arg(long, global = true)]
+    summarize: bool,
+    /// Start indexing without asking to confirm the cost estimate
+    #[arg(long, short = 'y', global = true)]
+    yes: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Index a directory and print what was extracted
+    Index {
+        /// Root directory of the codebase
+        dir: String,
+        /// Also write the embeddings to the Qdrant server at this URL, replacing what its collections held, so
+        /// `eve search --qdrant` can search them later without indexing
+        #[arg(long)]
+        qdrant: Option<String>,
+        /// Prefix of the Qdrant collections holding files, objects and morphisms
+        #[arg(long, default_value = "eve")]
+        collection: String,
+    },
+    /// Index a directory and write Markdown documentation of everything extracted
+    Docs {
+        /// Root directory of the codebase
+        dir: String,
+        /// File to write, stdout when omitted
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Index a directory, then keep re-indexing files as they change
+    Watch {
+        /// Root directory of the codebase
+        dir: String,
+    },
+    /// Index a directory, then answer queries read from stdin until EOF or :quit
+    Repl {
+        /// Directory to index before searching
+        #[arg(default_value = ".")]
+        root: String,
+        /// Kind of item to search for, change it in the session with :type
+        #[arg(long = "type", value_enum, default_value_t = QueryType::Morphism)]
+        query_type: QueryType,
+        /// Number of results to show, change it in the session with :top
+        #[arg(long, default_value_t = 10)]
+        top_k: usize,
+        /// Weight of keyword matching against names and descriptions, 0 is pure semantic search
+        #[arg(long, default_value_t = 0.0)]
+        keyword_weight: f32,
+        /// Rewrite the query with the LLM before searching, costs one extra request per query
+        #[arg(long)]
+        expand: bool,
+        /// Search an approximate nearest neighbour index instead of scanning every embedding, built once for the
+        /// whole session
+        #[arg(long)]
+        ann: bool,
+    },
+    /// Index a directory and search it, or search the Qdrant collections `eve index --qdrant` filled
+    Search {
+        query: String,
+        /// Kind of item to search for
+        #[arg(long = "type", value_enum, default_value_t = QueryType::Morphism)]
+        query_type: QueryType,
+        /// Number of results to show, 0 shows everything
+        #[arg(long, default_value_t = 10)]
+        top_k: usize,
+        /// Number of best results to skip, pass multiples of --top-k to page through results
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Drop results scoring below this. Cosine scores range from -1 to 1, 0 drops the ones pointing away from the query.
+        /// Unset keeps every result.
+        #[arg(long, allow_negative_numbers = true)]
+        min_score: Option<f32>,
+        /// Weight of keyword matching against names and descriptions, 0 is pure semantic search. Not available with
+        /// --qdrant, which only ranks by embedding.
+        #[arg(long, default_value_t = 0.0, conflicts_with = "qdrant")]
+        keyword_weight: f32,
+        /// Rewrite the query with the LLM before searching, costs one extra request per query
+        #[arg(long)]
+        expand: bool,
+        /// Let the LLM reorder the best candidates of every category, costs one extra request per category
+        #[arg(long)]
+        rerank: bool,
+        /// Search the collections `eve index --qdrant` filled on the Qdrant server at this URL instead of indexing
+        #[arg(long)]
+        qdrant: Option<String>,
+        /// Prefix of the Qdrant collections holding files, objects and morphisms
+        #[arg(long, default_value = "eve")]
+        collection: String,
+        /// How results are printed
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        /// Directory to index before searching
+        #[arg(long, default_value = ".", conflicts_with = "qdrant")]
+        root: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ProviderKind {
+    Openai,
+    // Embeddings still go through OpenAI, Anthropic has no embeddings endpoint
+    Anthropic,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChunkMode {
+    WholeFile,
+    PerSymbol,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum QueryType {
+    File,
+    Object,
+    Morphism,
+    All,
+}
+
+impl QueryType {
+    fn as_str(self) -> &'static str {
+        match self {
+            QueryType::File => "file",
+            QueryType::Object => "object",
+            QueryType::Morphism => "morphism",
+            QueryType::All => "all",
+        }
+    }
+}
+
 fn main() {
-    let files = indexer::list_files("/home/liyu-zerihun/EVE/src/test_src");
-    let mut codebase = indexer::code_base{
-    	files: Vec::new(),
-    	objects: Vec::new(),
-    	morphisms: Vec::new(),
+    // Warnings show by default, RUST_LOG=debug adds retries and cache hits, trace every similarity score
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let cli = Cli::parse();
+    let mut config = llm::LlmConfig {
+        max_concurrent_requests: cli.max_concurrent_requests,
+        max_file_bytes: cli.max_file_bytes,
+        request_timeout_secs: cli.request_timeout_secs,
+        chunk_mode: match cli.chunk_mode {
+            ChunkMode::WholeFile => llm::ChunkMode::WholeFile,
+            ChunkMode::PerSymbol => llm::ChunkMode::PerSymbol,
+        },
+        ..Default::default()
+    };
+    if let Some(path) = &cli.prompt_file {
+        match std::fs::read_to_string(path) {
+            Ok(prompt) if !prompt.trim().is_empty() => config.prompt = prompt,
+            Ok(_) => {
+                eprintln!("Prompt file {} is empty", path);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("Failed to read prompt file {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let provider: Box<dyn llm::LlmProvider> = match cli.provider {
+        ProviderKind::Openai => {
+            if let Some(model) = cli.model {
+                config.model = model;
+            }
+            Box::new(llm::OpenAiProvider::new(config.clone()))
+        }
+        ProviderKind::Anthropic => {
+            let embedder = Box::new(llm::OpenAiProvider::new(config.clone()));
+            config.model = cli.model.unwrap_or_else(|| "claude-sonnet-4-5".to_string());
+            Box::new(llm::AnthropicProvider::new(config.clone(), embedder))
+        }
+    };
+    let provider: Box<dyn llm::LlmProvider> = if cli.no_cache {
+        provider
+    } else {
+        Box::new(cache::CachedProvider::new(provider, &cli.cache_dir, &config.model, &config.embedding_model, &config.prompt))
+    };
+    let index_options = IndexOptions {
+        yes: cli.yes,
+        dry_run: cli.dry_run,
+        gitignore: cli.gitignore,
+        dedup_threshold: cli.dedup_threshold,
+        summarize: cli.summarize,
+        checkpoint: cli.checkpoint || cli.checkpoint_file.is_some(),
+        checkpoint_file: cli.checkpoint_file,
+        checkpoint_every: cli.checkpoint_every,
+    };
+    match cli.command {
+        Command::Index { dir, qdrant, collection } => {
+            let codebase = index_or_exit(&dir, provider.as_ref(), &config, std::sync::Arc::new(eve::StdoutReporter), &index_options);
+            print!("{}", codebase.stats());
+            println!("\n{}", codebase.summary.trim_end());
+            if let Some(url) = qdrant {
+                if let Err(err) = store::CodebaseStores::qdrant(&url, &collection, &config).fill(&codebase) {
+                    eprintln!("Qdrant at {} failed: {}", url, err);
+                    std::process::exit(1);
+                }
+                println!("Uploaded to the {}_* collections at {}", collection, url);
+            }
+            exit_if_failed(&codebase);
+        }
+        Command::Docs { dir, output } => {
+            let reporter: std::sync::Arc<dyn eve::ProgressReporter> = match output {
+                Some(_) => std::sync::Arc::new(eve::StdoutReporter),
+                None => std::sync::Arc::new(eve::StderrReporter),
+            };
+            let codebase = index_or_exit(&dir, provider.as_ref(), &config, reporter, &index_options);
+            let markdown = codebase.to_markdown();
+            match output {
+                Some(path) => {
+                    if let Err(err) = std::fs::write(&path, markdown) {
+                        eprintln!("Cannot write {}: {}", path, err);
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{}", markdown),
+            }
+            exit_if_failed(&codebase);
+        }
+        Command::Watch { dir } => {
+            let mut codebase = index_or_exit(&dir, provider.as_ref(), &config, std::sync::Arc::new(eve::StdoutReporter), &index_options);
+            report_failures(&codebase);
+            println!("Watching {} for changes", dir);
+            let watched = watch::watch(&dir, &mut codebase, provider.as_ref(), &config, index_options.gitignore, |codebase, updated| {
+                println!(
+                    "Re-indexed {}, now {} files: {} objects, {} morphisms",
+                    updated.join(", "),
+                    codebase.files.len(),
+                    codebase.objects.len(),
+                    codebase.morphisms.len()
+                );
+            });
+            if let Err(err) = watched {
+                eprintln!("Cannot watch {}: {}", dir, err);
+                std::process::exit(1);
+            }
+        }
+        Command::Repl { root, query_type, top_k, keyword_weight, expand, ann } => {
+            let mut codebase = index_or_exit(&root, provider.as_ref(), &config, std::sync::Arc::new(eve::StdoutReporter), &index_options);
+            report_failures(&codebase);
+            // Worth building up front only here, where every query of the session reuses it
+            if ann {
+                codebase.ann_index = Some(codebase.build_ann_index());
+            }
+            llm::runtime().block_on(repl(&codebase, query_type, top_k, keyword_weight, expand, provider.as_ref()));
+        }
+        Command::Search { query, query_type, top_k, offset, min_score, keyword_weight, expand, rerank, qdrant, collection, format, root } => {
+            // Progress goes to stderr when stdout has to stay parseable
+            let reporter: std::sync::Arc<dyn eve::ProgressReporter> = match format {
+                OutputFormat::Text => std::sync::Arc::new(eve::StdoutReporter),
+                OutputFormat::Json => std::sync::Arc::new(eve::StderrReporter),
+            };
+            // A Qdrant search reads what `eve index --qdrant` stored, nothing is indexed
+            let codebase = qdrant.is_none().then(|| index_or_exit(&root, provider.as_ref(), &config, reporter, &index_options));
+            let min_score = min_score.unwrap_or(f32::NEG_INFINITY);
+            let searched = if expand { llm::runtime().block_on(llm::expand_query(&query, provider.as_ref())) } else { query.clone() };
+            // Each category is ranked up to the end of the page, or further for reranking, then the hits before the
+            // offset are dropped. Qdrant searches are paged the same way.
+            let mut ranked = if top_k == 0 { 0 } else { offset.saturating_add(top_k) };
+            if rerank && ranked > 0 {
+                ranked = ranked.max(eve::RERANK_CANDIDATES);
+            }
+            let mut results = match (&qdrant, &codebase) {
+                (Some(url), _) => search_qdrant(url, &collection, &config, searched, query_type, ranked, min_score, provider.as_ref()),
+                (None, Some(codebase)) => eve::search_codebase_hybrid(codebase, searched, &query, query_type.as_str(), ranked, min_score, keyword_weight, provider.as_ref()),
+                (None, None) => unreachable!("the code base is indexed whenever Qdrant is not searched"),
+            };
+            if rerank {
+                let provider = provider.as_ref();
+                let files = matches!(query_type, QueryType::File | QueryType::All);
+                let objects = matches!(query_type, QueryType::Object | QueryType::All);
+                let morphisms = matches!(query_type, QueryType::Morphism | QueryType::All);
+                // One LLM request per category the query type returns, `all` sends its three concurrently
+                llm::runtime().block_on(async {
+                    let (relevant_files, relevant_objects, relevant_morphisms) = tokio::join!(
+                        rerank_if(files, &query, std::mem::take(&mut results.relevant_files), provider),
+                        rerank_if(objects, &query, std::mem::take(&mut results.relevant_objects), provider),
+                        rerank_if(morphisms, &query, std::mem::take(&mut results.relevant_morphisms), provider),
+                    );
+                    results.relevant_files = relevant_files;
+                    results.relevant_objects = relevant_objects;
+                    results.relevant_morphisms = relevant_morphisms;
+                });
+            }
+            results.relevant_files = eve::page(results.relevant_files, offset, top_k);
+            results.relevant_objects = eve::page(results.relevant_objects, offset, top_k);
+            results.relevant_morphisms = eve::page(results.relevant_morphisms, offset, top_k);
+            match format {
+                OutputFormat::Text => print_results(&query, query_type, &results),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&results.to_json()).unwrap()),
+            }
+            if let Some(codebase) = &codebase {
+                exit_if_failed(codebase);
+            }
+        }
+    }
+}
+
+// Reranks one category of hits when the query asked for it, otherwise hands them back untouched
+async fn rerank_if<T: eve::Embeddable>(wanted: bool, query: &str, hits: Vec<(T, f32)>, provider: &dyn llm::LlmProvider) -> Vec<(T, f32)> {
+    if wanted {
+        eve::rerank(query, hits, eve::RERANK_CANDIDATES, provider).await
+    } else {
+        hits
+    }
+}
+
+// Searches the collections an earlier `eve index --qdrant` filled, any store error ends the command
+#[allow(clippy::too_many_arguments)]
+fn search_qdrant(url: &str, collection: &str, config: &llm::LlmConfig, query: String, query_type: QueryType, top_k: usize, min_score: f32, provider: &dyn llm::LlmProvider) -> eve::query_result {
+    let searched = store::CodebaseStores::open_qdrant(url, collection, config)
+        .and_then(|stores| store::search_codebase_store(&stores, query, query_type.as_str(), top_k, min_score, provider));
+    match searched {
+        Ok(results) => results,
+        Err(err) => {
+            eprintln!("Qdrant at {} failed: {}", url, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Lists the files missing from the index on stderr, returns whether there were any
+fn report_failures(codebase: &eve::code_base) -> bool {
+    match codebase.failure_report() {
+        Some(report) => {
+            eprint!("{}", report);
+            true
+        }
+        None => false,
+    }
+}
+
+// One-shot commands still print what they have, then fail so scripts notice the index is incomplete
+fn exit_if_failed(codebase: &eve::code_base) {
+    if report_failures(codebase) {
+        std::process::exit(1);
+    }
+}
+
+// Global flags deciding how index_or_exit runs
+struct IndexOptions {
+    yes: bool,
+    dry_run: bool,
+    gitignore: bool,
+    dedup_threshold: Option<f32>,
+    summarize: bool,
+    checkpoint: bool,
+    checkpoint_file: Option<String>,
+    checkpoint_every: usize,
+}
+
+fn index_or_exit(root: &str, provider: &dyn llm::LlmProvider, config: &llm::LlmConfig, reporter: std::sync::Arc<dyn eve::ProgressReporter>, options: &IndexOptions) -> eve::code_base {
+    // The dry run lists exactly what indexing would walk
+    let listed = if options.gitignore {
+        eve::list_files_gitignore_filtered(root, &eve::source_extensions(), eve::DEFAULT_IGNORE_DIRS)
+    } else {
+        eve::list_files_filtered(root, &eve::source_extensions(), eve::DEFAULT_IGNORE_DIRS)
+    };
+    let files = match listed {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Cannot index {}: {}", root, err);
+            std::process::exit(1);
+        }
     };
+    if options.dry_run {
+        print_dry_run(&files, config);
+        std::process::exit(0);
+    }
+    if !options.yes && !confirm_cost(&eve::estimate_cost(&files, config)) {
+        eprintln!("Indexing cancelled");
+        std::process::exit(1);
+    }
+    let checkpoint = options.checkpoint.then(|| {
+        let path = match &options.checkpoint_file {
+            Some(path) => std::path::PathBuf::from(path),
+            None => checkpoint::default_path(std::path::Path::new(root)),
+        };
+        let checkpoint = checkpoint::Checkpoint::new(path, options.checkpoint_every).with_root(std::path::Path::new(root));
+        if let Err(err) = checkpoint.ensure_same_root() {
+            eprintln!("Cannot resume from checkpoint: {}", err);
+            std::process::exit(1);
+        }
+        if checkpoint.exists() {
+            eprintln!("Resuming from checkpoint {}", checkpoint.path.display());
+        }
+        checkpoint::install_interrupt_handler();
+        checkpoint
+    });
+    let mut codebase = eve::index_files_with_checkpoint(&files, provider, config, reporter, checkpoint.as_ref());
+    if let Some(checkpoint) = &checkpoint
+        && checkpoint::interrupted()
+    {
+        eprintln!("Progress saved to {}, run the same command again to resume", checkpoint.path.display());
+        std::process::exit(130);
+    }
+    if let Some(threshold) = options.dedup_threshold {
+        let removed = codebase.dedup(threshold);
+        log::info!("Collapsed {} duplicate objects and morphisms", removed);
+    }
+    codebase.summarize(options.summarize.then_some(provider));
+    codebase
+}
 
-    let num_files = files.len();
-    println!("Number of files found: {}", num_files);
-
-    let num_cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
-    println!("Number of CPU cores available: {}", num_cores);
-
-    let div : usize = (num_files as f32 / num_cores as f32).ceil() as usize;
-    println!("Dividing work into chunks of size: {}", div);
-
-    let mut start = 0;
-    let mut handles = vec![];
-    let num_indexed = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
-    for i in 0..num_cores {
-    	let end = if start + div > num_files { num_files } else { start + div };
-        let file_chunk = files[start..end].to_vec();
-        start = end;
-        let mut codebase_clone = codebase.clone();
-        let num_indexed = std::sync::Arc::clone(&num_indexed);
-        handles.push(thread::spawn(move || {
-            for file in &file_chunk {
-                //Increment the indexed file count
-                num_indexed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-
-                let input = std::fs::read_to_string(file).expect("Failed to read file");
-                let res = tokio::runtime::Runtime::new().unwrap().block_on(llm::request_llm(input));
-                let info_tuple = llm::parse_llm_response(res.unwrap(), file.to_string());
-                // Accumulate parsed info into the codebase clone
-                codebase_clone.files.push(info_tuple.0);
-                codebase_clone.objects.extend(info_tuple.1);
-                codebase_clone.morphisms.extend(info_tuple.2);
+// Prints every file indexing would send to the provider, and the ones the size or binary check would skip
+fn print_dry_run(files: &[String], config: &llm::LlmConfig) {
+    let mut indexed = 0;
+    for file in files {
+        match eve::should_skip(file, config) {
+            Some(reason) => eprintln!("Skipping {}: {}", file, reason),
+            None => {
+                println!("{}", file);
+                indexed += 1;
             }
-            codebase_clone
-        }));
-    }
-    // Spawn a thread to monitor progress
-    let num_indexed_clone = std::sync::Arc::clone(&num_indexed);
-    let progress_handle = thread::spawn(move || {
-        loop {
-            let count = num_indexed_clone.load(std::sync::atomic::Ordering::Relaxed);
-            print!("\rIndexed {}/{} files", count, num_files);
-            std::io::Write::flush(&mut std::io::stdout()).unwrap();
-            if count >= num_files {
-                println!();
+        }
+    }
+    println!("{} files would be indexed", indexed);
+}
+
+// Shows the estimate on stderr and waits for a yes, anything else (including end of input) cancels
+fn confirm_cost(estimate: &eve::CostEstimate) -> bool {
+    eprint!("Indexing {}. Continue? [y/N] ", estimate);
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+const REPL_HELP: &str = "Type a query to search. Commands: :type file|object|morphism|all, :top N, :help, :quit";
+
+// Every query is embedded on the shared runtime, there is no runtime built per line
+async fn repl(codebase: &eve::code_base, mut query_type: QueryType, mut top_k: usize, keyword_weight: f32, expand: bool, provider: &dyn llm::LlmProvider) {
+    use std::io::Write;
+    use tokio::io::AsyncBufReadExt;
+    println!("{}", REPL_HELP);
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("eve ({})> ", query_type.as_str());
+        let _ = std::io::stdout().flush();
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("Cannot read input: {}", err);
                 break;
             }
-            std::thread::sleep(std::time::Duration::from_secs(2));
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
         }
-    });
-    for handle in handles {
-        let partial_codebase = handle.join().unwrap();
-        codebase.files.extend(partial_codebase.files);
-        codebase.objects.extend(partial_codebase.objects);
-        codebase.morphisms.extend(partial_codebase.morphisms);
-    }
-    progress_handle.join().unwrap();
-
-    // Example search usage
-    let query : String = "A function that sends requests for autocompletion".to_string();
-    let query_type = "morphism";
-    let results = indexer::search_codebase(&codebase, query.clone(), query_type);
-    if results.relevant_morphisms.len() > 0 {
-    	println!("Search Results for query '{}' of type '{}':", query, query_type);
-    	for morphism in results.relevant_morphisms {
-    		println!("Morphism: {}, Description: {}", morphism.name, morphism.description);
-    	}
-    } else {
-    	println!("No relevant morphisms found for query '{}' of type '{}'.", query, query_type);
+        if let Some(command) = line.strip_prefix(':') {
+            let (name, arg) = command.split_once(' ').map_or((command, ""), |(name, arg)| (name, arg.trim()));
+            match name {
+                "quit" | "q" => break,
+                "help" => println!("{}", REPL_HELP),
+                "type" => match <QueryType as ValueEnum>::from_str(arg, true) {
+                    Ok(parsed) => query_type = parsed,
+                    Err(_) => eprintln!("Unknown type '{}', expected file, object, morphism or all", arg),
+                },
+                "top" => match arg.parse() {
+                    Ok(parsed) => top_k = parsed,
+                    Err(_) => eprintln!("Expected a number of results, got '{}'", arg),
+                },
+                _ => eprintln!("Unknown command :{}. {}", name, REPL_HELP),
+            }
+            continue;
+        }
+        let query = if expand { llm::expand_query(line, provider).await } else { line.to_string() };
+        let results = eve::search_codebase_hybrid_async(codebase, query, line, query_type.as_str(), top_k, f32::NEG_INFINITY, keyword_weight, provider).await;
+        print_results(line, query_type, &results);
+    }
+}
+
+fn print_results(query: &str, query_type: QueryType, results: &eve::query_result) {
+    if results.relevant_files.is_empty() && results.relevant_objects.is_empty() && results.relevant_morphisms.is_empty() {
+        println!("No relevant results found for query '{}' of type '{}'.", query, query_type.as_str());
+        return;
+    }
+    println!("Search Results for query '{}' of type '{}':", query, query_type.as_str());
+    for (file, score) in results.relevant_files.iter() {
+        println!("[{:.3}] File: {}, Description: {}", score, file.path, file.description);
+    }
+    for (object, score) in results.relevant_objects.iter() {
+        println!("[{:.3}] Object: {} ({}:{}), Description: {}", score, object.name, object.file_path, object.start_line, object.description);
+    }
+    for (morphism, score) in results.relevant_morphisms.iter() {
+        println!("[{:.3}] Morphism: {} ({}:{}), Description: {}", score, morphism.name, morphism.file_path, morphism.start_line, morphism.description);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,134 @@
+use crate::{indexer, llm};
+// Renders the index as Markdown documentation, one section per source file
+
+fn code_list(names: &[String]) -> String {
+	names.iter().map(|name| format!("`{}`", name)).collect::<Vec<_>>().join(", ")
+}
+
+fn line_range(start_line: usize, end_line: usize) -> String {
+	match (start_line, end_line) {
+		(0, _) => String::new(),
+		(start, end) if end > start => format!(", lines {}-{}", start, end),
+		(start, _) => format!(", line {}", start),
+	}
+}
+
+fn push_object(doc: &mut String, obj: &llm::Object) {
+	doc.push_str(&format!("#### `{}` ({}{})\n\n", obj.name, obj.obj_type, line_range(obj.start_line, obj.end_line)));
+	if !obj.description.is_empty() {
+		doc.push_str(&format!("{}\n\n", obj.description));
+	}
+	if !obj.morphisms.is_empty() {
+		doc.push_str(&format!("- Morphisms: {}\n", code_list(&obj.morphisms)));
+	}
+	if !obj.dependencies.is_empty() {
+		doc.push_str(&format!("- Depends on: {}\n", code_list(&obj.dependencies)));
+	}
+	if !obj.duplicate_file_paths.is_empty() {
+		doc.push_str(&format!("- Also defined in: {}\n", code_list(&obj.duplicate_file_paths)));
+	}
+	if !obj.morphisms.is_empty() || !obj.dependencies.is_empty() || !obj.duplicate_file_paths.is_empty() {
+		doc.push('\n');
+	}
+}
+
+fn push_morphism(doc: &mut String, morph: &llm::Morphism) {
+	let owner = morph.owner_object.as_ref().map(|owner| format!(" of `{}`", owner)).unwrap_or_default();
+	doc.push_str(&format!("#### `{}` ({}{}{})\n\n", morph.name, morph.morph_type, owner, line_range(morph.start_line, morph.end_line)));
+	if !morph.description.is_empty() {
+		doc.push_str(&format!("{}\n\n", morph.description));
+	}
+	if !morph.dependencies.is_empty() {
+		doc.push_str(&format!("- Depends on: {}\n\n", code_list(&morph.dependencies)));
+	}
+}
+
+impl indexer::code_base {
+	// The summary, if any, then one section per file with its language and description, followed by the objects and morphisms extracted from
+	// it in declaration order. Records whose file is missing from `files` get a section of their own at the end.
+	pub fn to_markdown(&self) -> String {
+		let mut paths: Vec<&str> = self.files.iter().map(|file| file.path.as_str()).collect();
+		for path in self.objects.iter().map(|obj| obj.file_path.as_str()).chain(self.morphisms.iter().map(|morph| morph.file_path.as_str())) {
+			if !paths.contains(&path) {
+				paths.push(path);
+			}
+		}
+		let mut doc = String::from("# Codebase documentation\n\n");
+		if !self.summary.is_empty() {
+			doc.push_str(&format!("{}\n\n", self.summary.trim_end()));
+		}
+		for path in paths {
+			doc.push_str(&format!("## {}\n\n", path));
+			if let Some(file) = self.files.iter().find(|file| file.path == path) {
+				if !file.language.is_empty() {
+					doc.push_str(&format!("*Language: {}*\n\n", file.language));
+				}
+				if !file.description.is_empty() {
+					doc.push_str(&format!("{}\n\n", file.description));
+				}
+			}
+			let objects: Vec<&llm::Object> = self.objects.iter().filter(|obj| obj.file_path == path).collect();
+			if !objects.is_empty() {
+				doc.push_str("### Objects\n\n");
+				for obj in objects {
+					push_object(&mut doc, obj);
+				}
+			}
+			let morphisms: Vec<&llm::Morphism> = self.morphisms.iter().filter(|morph| morph.file_path == path).collect();
+			if !morphisms.is_empty() {
+				doc.push_str("### Morphisms\n\n");
+				for morph in morphisms {
+					push_morphism(&mut doc, morph);
+				}
+			}
+		}
+		doc
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn markdown_groups_records_under_their_file() {
+		let code_base = indexer::code_base {
+			files: vec![llm::File {
+				path: "src/dog.py".to_string(),
+				language: "Python".to_string(),
+				description: "Dogs.".to_string(),
+				..Default::default()
+			}],
+			objects: vec![llm::Object {
+				name: "Dog".to_string(),
+				obj_type: "class".to_string(),
+				morphisms: vec!["speak".to_string()],
+				dependencies: vec!["Animal".to_string()],
+				description: "A dog.".to_string(),
+				file_path: "src/dog.py".to_string(),
+				start_line: 1,
+				end_line: 3,
+				..Default::default()
+			}],
+			morphisms: vec![llm::Morphism {
+				name: "speak".to_string(),
+				morph_type: "method".to_string(),
+				description: "Barks.".to_string(),
+				file_path: "src/dog.py".to_string(),
+				start_line: 2,
+				end_line: 3,
+				owner_object: Some("Dog".to_string()),
+				..Default::default()
+			}],
+			..Default::default()
+		};
+		let doc = code_base.to_markdown();
+		let expected = "# Codebase documentation\n\n## src/dog.py\n\n*Language: Python*\n\nDogs.\n\n### Objects\n\n\
+			#### `Dog` (class, lines 1-3)\n\nA dog.\n\n- Morphisms: `speak`\n- Depends on: `Animal`\n\n\
+			### Morphisms\n\n#### `speak` (method of `Dog`, lines 2-3)\n\nBarks.\n\n";
+		assert_eq!(doc, expected);
+
+		let summarized = indexer::code_base { summary: "Models pets.\n".to_string(), ..code_base };
+		assert!(summarized.to_markdown().starts_with("# Codebase documentation\n\nModels pets.\n\n## src/dog.py\n\n"));
+	}
+}
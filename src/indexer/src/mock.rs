@@ -0,0 +1,97 @@
+use crate::llm::{LlmError, LlmProvider};
+use std::sync::atomic::{AtomicUsize, Ordering};
+// Offline provider with canned answers, so indexing and search can be exercised without network access or API keys
+
+// Length of the vectors returned for texts without an explicit embedding
+pub const MOCK_DIMENSIONS: usize = 64;
+
+#[derive(Default)]
+pub struct MockProvider {
+	// (needle, response) pairs, canonicalize answers with the first response whose needle occurs in the code
+	pub responses: Vec<(String, String)>,
+	// Exact texts with a fixed embedding, any other text gets a bag of words vector
+	pub embeddings: Vec<(String, Vec<f32>)>,
+	// Completions returned for every prompt, None makes complete fail like a provider without completions
+	pub completion: Option<String>,
+	canonicalize_calls: AtomicUsize,
+	embed_calls: AtomicUsize,
+}
+
+impl MockProvider {
+	pub fn new() -> Self {
+		MockProvider::default()
+	}
+
+	pub fn with_response(mut self, needle: &str, response: &str) -> Self {
+		self.responses.push((needle.to_string(), response.to_string()));
+		self
+	}
+
+	pub fn with_embedding(mut self, text: &str, embedding: Vec<f32>) -> Self {
+		self.embeddings.push((text.to_string(), embedding));
+		self
+	}
+
+	pub fn with_completion(mut self, completion: &str) -> Self {
+		self.completion = Some(completion.to_string());
+		self
+	}
+
+	pub fn canonicalize_calls(&self) -> usize {
+		self.canonicalize_calls.load(Ordering::SeqCst)
+	}
+
+	pub fn embed_calls(&self) -> usize {
+		self.embed_calls.load(Ordering::SeqCst)
+	}
+}
+
+// Every lowercase word adds one to a bucket picked by its hash, so texts sharing words point in similar directions
+pub fn bag_of_words(text: &str) -> Vec<f32> {
+	let mut vector = vec![0.0; MOCK_DIMENSIONS];
+	for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+		// FNV-1a, stable across runs and platforms unlike the std hasher
+		let hash = word.to_lowercase().bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+		vector[(hash % MOCK_DIMENSIONS as u64) as usize] += 1.0;
+	}
+	vector
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for MockProvider {
+	async fn canonicalize(&self, code: String) -> Result<String, LlmError> {
+		self.canonicalize_calls.fetch_add(1, Ordering::SeqCst);
+		self.responses
+			.iter()
+			.find(|(needle, _)| code.contains(needle.as_str()))
+			.map(|(_, response)| response.clone())
+			.ok_or_else(|| "No canned response matches this code".into())
+	}
+
+	async fn embed(&self, text: String) -> Result<Vec<f32>, LlmError> {
+		self.embed_calls.fetch_add(1, Ordering::SeqCst);
+		let fixed = self.embeddings.iter().find(|(known, _)| *known == text);
+		Ok(fixed.map_or_else(|| bag_of_words(&text), |(_, embedding)| embedding.clone()))
+	}
+
+	async fn complete(&self, _prompt: String) -> Result<String, LlmError> {
+		self.completion.clone().ok_or_else(|| "No canned completion".into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn canned_answers_and_stable_embeddings() {
+		let provider = MockProvider::new().with_response("fn main", "{}").with_embedding("pinned", vec![1.0]);
+		let runtime = crate::llm::runtime();
+		assert_eq!(runtime.block_on(provider.canonicalize("fn main() {}".to_string())).unwrap(), "{}");
+		assert!(runtime.block_on(provider.canonicalize("class A: pass".to_string())).is_err());
+		assert_eq!(runtime.block_on(provider.embed("pinned".to_string())).unwrap(), vec![1.0]);
+		assert_eq!(bag_of_words("Send HTTP request"), bag_of_words("request: send http"));
+		assert_eq!(provider.canonicalize_calls(), 2);
+		assert_eq!(provider.embed_calls(), 1);
+	}
+}
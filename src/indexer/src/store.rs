@@ -0,0 +1,399 @@
+use crate::indexer::{self, cosine_similarity, Embeddable};
+use crate::llm::{self, LlmError};
+// Vector backends the embeddings of an index can be written to and searched in, in memory like code_base itself or
+// in a Qdrant server so a large index outlives the process and can be shared. search_codebase keeps scanning the
+// code base directly: keyword blending, exact name matches and the ANN index need every record, which a store only
+// hands back for its top_k. InMemoryStore sorts and cuts its hits with the same code, so both paths rank alike.
+
+// One search result of a store, the payload is what was stored with the embedding
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreHit {
+	pub id: u64,
+	pub score: f32,
+	pub payload: serde_json::Value,
+}
+
+pub trait VectorStore: Send + Sync {
+	// Stores the embedding under `id`, replacing an earlier one together with its payload
+	fn add(&mut self, id: u64, embedding: &[f32], payload: serde_json::Value) -> Result<(), LlmError>;
+	// Makes buffered writes visible to search, a no-op for stores that write through
+	fn flush(&mut self) -> Result<(), LlmError> {
+		Ok(())
+	}
+	// Drops every stored embedding, so records removed from an index do not outlive it in the store
+	fn clear(&mut self) -> Result<(), LlmError>;
+	// The stored embeddings most similar to the query by cosine, best first. A top_k of 0 returns everything.
+	fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<StoreHit>, LlmError>;
+}
+
+// Keeps every embedding in a Vec and scans them all, the same ranking search_codebase does without keywords
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStore {
+	vectors: Vec<(u64, Vec<f32>, serde_json::Value)>,
+	// Position of every id in `vectors`, so replacing one does not scan them all
+	positions: std::collections::HashMap<u64, usize>,
+}
+
+impl InMemoryStore {
+	pub fn new() -> Self {
+		InMemoryStore::default()
+	}
+
+	pub fn len(&self) -> usize {
+		self.vectors.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.vectors.is_empty()
+	}
+}
+
+impl VectorStore for InMemoryStore {
+	fn add(&mut self, id: u64, embedding: &[f32], payload: serde_json::Value) -> Result<(), LlmError> {
+		match self.positions.get(&id) {
+			Some(&position) => self.vectors[position] = (id, embedding.to_vec(), payload),
+			None => {
+				self.positions.insert(id, self.vectors.len());
+				self.vectors.push((id, embedding.to_vec(), payload));
+			}
+		}
+		Ok(())
+	}
+
+	fn clear(&mut self) -> Result<(), LlmError> {
+		self.vectors.clear();
+		self.positions.clear();
+		Ok(())
+	}
+
+	// Vectors that cannot be compared with the query are left out, as in search_codebase
+	fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<StoreHit>, LlmError> {
+		let scored: Vec<((u64, &serde_json::Value), f32, bool)> = self
+			.vectors
+			.iter()
+			.filter_map(|(id, embedding, payload)| Some(((*id, payload), cosine_similarity(query, embedding)?, false)))
+			.collect();
+		Ok(indexer::finish_ranking(scored, 0, top_k)
+			.into_iter()
+			.map(|((id, payload), score)| StoreHit { id, score, payload: payload.clone() })
+			.collect())
+	}
+}
+
+// Points are sent in batches of this many, one request per point is far too slow for a whole index
+pub const QDRANT_BATCH_SIZE: usize = 256;
+// Qdrant needs an explicit limit, this stands in for a top_k of 0
+const QDRANT_MAX_LIMIT: usize = 10_000;
+
+// One collection of a Qdrant server, spoken to over its REST API. The collection is created with cosine distance on
+// the first flush if it does not exist yet, clear deletes it. QDRANT_API_KEY is sent when set, for Qdrant Cloud.
+pub struct QdrantStore {
+	pub url: String,
+	pub collection: String,
+	// Timeout and retry settings for the HTTP requests
+	config: llm::LlmConfig,
+	pending: Vec<serde_json::Value>,
+	collection_ready: bool,
+}
+
+impl QdrantStore {
+	pub fn new(url: &str, collection: &str, config: &llm::LlmConfig) -> Self {
+		QdrantStore {
+			url: url.trim_end_matches('/').to_string(),
+			collection: collection.to_string(),
+			config: config.clone(),
+			pending: Vec::new(),
+			collection_ready: false,
+		}
+	}
+
+	fn endpoint(&self, path: &str) -> String {
+		format!("{}/collections/{}{}", self.url, self.collection, path)
+	}
+
+	// Sends a JSON body and returns the "result" field of the answer
+	async fn request(&self, method: reqwest::Method, path: &str, body: Option<&serde_json::Value>) -> Result<serde_json::Value, LlmError> {
+		let client = llm::http_client(&self.config)?;
+		let api_key = std::env::var("QDRANT_API_KEY").ok();
+		let url = self.endpoint(path);
+		let res = llm::send_with_retry(|| {
+			let mut request = client.request(method.clone(), url.as_str());
+			if let Some(api_key) = &api_key {
+				request = request.header("api-key", api_key.as_str());
+			}
+			if let Some(body) = body {
+				request = request.json(body);
+			}
+			request
+		}, &self.config).await?;
+		let res_json: serde_json::Value = res.json().await?;
+		Ok(res_json["result"].clone())
+	}
+
+	async fn exists(&self) -> Result<bool, LlmError> {
+		let exists = self.request(reqwest::Method::GET, "/exists", None).await?;
+		Ok(exists["exists"].as_bool() == Some(true))
+	}
+
+	async fn ensure_collection(&self, dim: usize) -> Result<(), LlmError> {
+		if self.exists().await? {
+			return Ok(());
+		}
+		let body = serde_json::json!({ "vectors": { "size": dim, "distance": "Cosine" } });
+		self.request(reqwest::Method::PUT, "", Some(&body)).await?;
+		Ok(())
+	}
+
+	async fn upsert(&self, points: Vec<serde_json::Value>) -> Result<(), LlmError> {
+		let body = serde_json::json!({ "points": points });
+		self.request(reqwest::Method::PUT, "/points?wait=true", Some(&body)).await?;
+		Ok(())
+	}
+}
+
+impl VectorStore for QdrantStore {
+	fn add(&mut self, id: u64, embedding: &[f32], payload: serde_json::Value) -> Result<(), LlmError> {
+		self.pending.push(serde_json::json!({ "id": id, "vector": embedding, "payload": payload }));
+		if self.pending.len() >= QDRANT_BATCH_SIZE {
+			self.flush()?;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), LlmError> {
+		if self.pending.is_empty() {
+			return Ok(());
+		}
+		let points = std::mem::take(&mut self.pending);
+		llm::runtime().block_on(async {
+			if !self.collection_ready {
+				let dim = points[0]["vector"].as_array().map_or(0, |vector| vector.len());
+				self.ensure_collection(dim).await?;
+			}
+			self.upsert(points).await
+		})?;
+		self.collection_ready = true;
+		Ok(())
+	}
+
+	// The collection is dropped rather than emptied point by point, the next flush creates it again with the
+	// dimension of the new embeddings
+	fn clear(&mut self) -> Result<(), LlmError> {
+		self.pending.clear();
+		llm::runtime().block_on(async {
+			if self.exists().await? {
+				self.request(reqwest::Method::DELETE, "", None).await?;
+			}
+			Ok::<(), LlmError>(())
+		})?;
+		self.collection_ready = false;
+		Ok(())
+	}
+
+	fn search(&self, query: &[f32], top_k: usize) -> Result<Vec<StoreHit>, LlmError> {
+		let limit = if top_k == 0 { QDRANT_MAX_LIMIT } else { top_k };
+		let body = serde_json::json!({ "vector": query, "limit": limit, "with_payload": true });
+		let result = llm::runtime().block_on(self.request(reqwest::Method::POST, "/points/search", Some(&body)))?;
+		let points = result.as_array().ok_or("No search results in Qdrant response")?;
+		Ok(points
+			.iter()
+			.filter_map(|point| {
+				Some(StoreHit {
+					id: point["id"].as_u64()?,
+					score: point["score"].as_f64()? as f32,
+					payload: point["payload"].clone(),
+				})
+			})
+			.collect())
+	}
+}
+
+// One store per kind of record, mirroring how code_base and AnnIndex keep them apart. Every point carries its whole
+// record, so a filled store answers searches without the code base it came from.
+pub struct CodebaseStores<S: VectorStore> {
+	pub files: S,
+	pub objects: S,
+	pub morphisms: S,
+}
+
+impl CodebaseStores<InMemoryStore> {
+	pub fn in_memory() -> Self {
+		CodebaseStores {
+			files: InMemoryStore::new(),
+			objects: InMemoryStore::new(),
+			morphisms: InMemoryStore::new(),
+		}
+	}
+}
+
+impl CodebaseStores<QdrantStore> {
+	// Three collections named after `collection`, e.g. eve_files, eve_objects and eve_morphisms
+	pub fn qdrant(url: &str, collection: &str, config: &llm::LlmConfig) -> Self {
+		CodebaseStores {
+			files: QdrantStore::new(url, &format!("{}_files", collection), config),
+			objects: QdrantStore::new(url, &format!("{}_objects", collection), config),
+			morphisms: QdrantStore::new(url, &format!("{}_morphisms", collection), config),
+		}
+	}
+
+	// The collections an earlier fill left behind, ready to be searched without indexing anything. Fails when one of
+	// them does not exist.
+	pub fn open_qdrant(url: &str, collection: &str, config: &llm::LlmConfig) -> Result<Self, LlmError> {
+		let mut stores = CodebaseStores::qdrant(url, collection, config);
+		for store in [&mut stores.files, &mut stores.objects, &mut stores.morphisms] {
+			if !llm::runtime().block_on(store.exists())? {
+				return Err(format!("Qdrant collection {} does not exist, fill it first", store.collection).into());
+			}
+			store.collection_ready = true;
+		}
+		Ok(stores)
+	}
+}
+
+// Id of a record that stays the same across runs as long as the record does, unlike its position in the code base.
+// `kind` is "file", "object" or "morphism" and files use an empty name and line 0. Records the LLM could not locate
+// all sit on line 0, `ordinal` counts the earlier records of the same kind, file, name and line so they stay apart.
+pub fn point_id(kind: &str, file_path: &str, name: &str, start_line: usize, ordinal: usize) -> u64 {
+	let hash = crate::cache::content_hash(file_path, &format!("{}\0{}\0{}\0{}", kind, name, start_line, ordinal));
+	u64::from_str_radix(&hash[..16], 16).unwrap_or_default()
+}
+
+// Writes every record that has an embedding under its point_id. The payload is the record without its embedding,
+// which the store already holds as the vector.
+fn add_records<T: Embeddable + serde::Serialize>(store: &mut impl VectorStore, kind: &str, records: &[T], key: impl Fn(&T) -> (&str, &str, usize)) -> Result<(), LlmError> {
+	let mut seen: std::collections::HashMap<(&str, &str, usize), usize> = std::collections::HashMap::new();
+	for record in records {
+		if record.embedding().is_empty() {
+			continue;
+		}
+		let mut payload = serde_json::to_value(record)?;
+		if let Some(fields) = payload.as_object_mut() {
+			fields.remove("embedding");
+		}
+		let (file_path, name, start_line) = key(record);
+		let ordinal = seen.entry((file_path, name, start_line)).or_default();
+		store.add(point_id(kind, file_path, name, start_line, *ordinal), record.embedding(), payload)?;
+		*ordinal += 1;
+	}
+	store.flush()
+}
+
+impl<S: VectorStore> CodebaseStores<S> {
+	// Replaces whatever the stores held with the records of `code_base`
+	pub fn fill(&mut self, code_base: &indexer::code_base) -> Result<(), LlmError> {
+		self.files.clear()?;
+		self.objects.clear()?;
+		self.morphisms.clear()?;
+		add_records(&mut self.files, "file", &code_base.files, |file| (file.path.as_str(), "", 0))?;
+		add_records(&mut self.objects, "object", &code_base.objects, |obj| (obj.file_path.as_str(), obj.name.as_str(), obj.start_line))?;
+		add_records(&mut self.morphisms, "morphism", &code_base.morphisms, |morph| (morph.file_path.as_str(), morph.name.as_str(), morph.start_line))
+	}
+}
+
+// Turns store hits back into records from their payloads, hits whose payload is not such a record are dropped and
+// so are those scoring below min_score
+fn resolve<T: serde::de::DeserializeOwned>(hits: Vec<StoreHit>, min_score: f32) -> Vec<(T, f32)> {
+	hits.into_iter()
+		.filter(|hit| hit.score >= min_score)
+		.filter_map(|hit| match serde_json::from_value(hit.payload) {
+			Ok(record) => Some((record, hit.score)),
+			Err(err) => {
+				log::warn!("Skipping store point {}: {}", hit.id, err);
+				None
+			}
+		})
+		.collect()
+}
+
+// search_codebase with the ranking delegated to the stores, query_type, top_k and min_score mean the same. Hits are
+// sorted by score, so dropping those below min_score after the store cut its top_k keeps the same ones. The records
+// come back without their embeddings. Store errors are returned rather than reported, a remote backend being down is
+// worth telling the caller about.
+pub fn search_codebase_store<S: VectorStore>(
+	stores: &CodebaseStores<S>,
+	query: String,
+	query_type: &str,
+	top_k: usize,
+	min_score: f32,
+	provider: &dyn llm::LlmProvider,
+) -> Result<indexer::query_result, LlmError> {
+	let query_vec = llm::runtime().block_on(provider.embed(query))?;
+	let mut result = indexer::query_result::default();
+	if query_type == "file" || query_type == "all" {
+		result.relevant_files = resolve(stores.files.search(&query_vec, top_k)?, min_score);
+	}
+	if query_type == "object" || query_type == "all" {
+		result.relevant_objects = resolve(stores.objects.search(&query_vec, top_k)?, min_score);
+	}
+	if query_type == "morphism" || query_type == "all" {
+		result.relevant_morphisms = resolve(stores.morphisms.search(&query_vec, top_k)?, min_score);
+	}
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn in_memory_store_ranks_like_search_codebase() {
+		let object = |name: &str, line: usize, embedding: Vec<f32>| llm::Object {
+			name: name.to_string(),
+			file_path: "a.rs".to_string(),
+			start_line: line,
+			embedding,
+			..Default::default()
+		};
+		let mut code_base = indexer::code_base {
+			objects: vec![object("far", 1, vec![0.0, 1.0]), object("close", 5, vec![5.0, 1.0]), object("missing", 9, Vec::new()), object("near", 12, vec![3.0, 1.0])],
+			..Default::default()
+		};
+		let mut stores = CodebaseStores::in_memory();
+		stores.fill(&code_base).unwrap();
+		assert_eq!(stores.objects.len(), 3);
+		let provider = crate::mock::MockProvider::new().with_embedding("query", vec![5.0, 1.0]);
+		let names = |result: indexer::query_result| -> Vec<String> { result.relevant_objects.into_iter().map(|(obj, _)| obj.name).collect() };
+		let stored = search_codebase_store(&stores, "query".to_string(), "object", 2, f32::NEG_INFINITY, &provider).unwrap();
+		let scanned = indexer::search_codebase(&code_base, "query".to_string(), "object", 2, f32::NEG_INFINITY, &provider);
+		// Whole records come back from the payload, only the embedding is left out
+		assert_eq!(stored.relevant_objects[0].0.start_line, 5);
+		assert!(stored.relevant_objects[0].0.embedding.is_empty());
+		assert_eq!(names(stored), vec!["close", "near"]);
+		assert_eq!(names(scanned), vec!["close", "near"]);
+		// min_score cuts both the same way
+		let stored = search_codebase_store(&stores, "query".to_string(), "object", 0, 0.995, &provider).unwrap();
+		let scanned = indexer::search_codebase(&code_base, "query".to_string(), "object", 0, 0.995, &provider);
+		assert_eq!(names(stored), vec!["close"]);
+		assert_eq!(names(scanned), vec!["close"]);
+
+		// Ids follow the record rather than its position, and filling again drops records that are gone
+		code_base.objects.remove(0);
+		stores.fill(&code_base).unwrap();
+		assert_eq!(stores.objects.len(), 2);
+		let hits = stores.objects.search(&[5.0, 1.0], 0).unwrap();
+		assert_eq!(hits[0].id, point_id("object", "a.rs", "close", 5, 0));
+		assert_ne!(point_id("object", "a.rs", "close", 5, 0), point_id("object", "a.rs", "close", 6, 0));
+		assert_ne!(point_id("object", "a.rs", "close", 5, 0), point_id("morphism", "a.rs", "close", 5, 0));
+	}
+
+	#[test]
+	fn unlocated_records_with_the_same_name_do_not_collide() {
+		// Neither was located, so both sit on line 0 of the same file
+		let object = |description: &str, embedding: Vec<f32>| llm::Object {
+			name: "Handler".to_string(),
+			file_path: "a.rs".to_string(),
+			description: description.to_string(),
+			embedding,
+			..Default::default()
+		};
+		let code_base = indexer::code_base {
+			objects: vec![object("First.", vec![1.0, 0.0]), object("Second.", vec![0.0, 1.0])],
+			..Default::default()
+		};
+		let mut stores = CodebaseStores::in_memory();
+		stores.fill(&code_base).unwrap();
+		assert_eq!(stores.objects.len(), 2);
+		let ids: Vec<u64> = stores.objects.search(&[1.0, 0.0], 0).unwrap().into_iter().map(|hit| hit.id).collect();
+		assert_eq!(ids, vec![point_id("object", "a.rs", "Handler", 0, 0), point_id("object", "a.rs", "Handler", 0, 1)]);
+	}
+}
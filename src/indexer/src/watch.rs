@@ -0,0 +1,181 @@
+use crate::{indexer, llm};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+// Keeps an index up to date while files are being edited, only the files that changed are canonicalized again
+
+// Events closer together than this are handled as one batch, so an editor writing a file several times on save
+// triggers a single re-index
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Blocks until the next batch of changes and returns the distinct paths touched by it, or None once the watcher has
+// gone away. The batch ends after `window` passes without a new event.
+pub fn next_batch(events: &Receiver<notify::Result<notify::Event>>, window: Duration) -> Option<Vec<PathBuf>> {
+	let mut paths: Vec<PathBuf> = Vec::new();
+	let mut add = |event: notify::Result<notify::Event>| match event {
+		Ok(event) => {
+			for path in event.paths {
+				if !paths.contains(&path) {
+					paths.push(path);
+				}
+			}
+		}
+		Err(err) => log::warn!("Watch error: {}", err),
+	};
+	add(events.recv().ok()?);
+	loop {
+		match events.recv_timeout(window) {
+			Ok(event) => add(event),
+			Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return Some(paths),
+		}
+	}
+}
+
+// Maps an absolute event path back to the form list_files produces under `root`, None for paths indexing would
+// skip: other extensions, ignored directories or anything outside the root
+fn indexed_path(root: &str, canonical_root: &Path, path: &Path) -> Option<String> {
+	let relative = path.strip_prefix(canonical_root).ok()?;
+	let ignored = relative
+		.parent()
+		.is_some_and(|dirs| dirs.iter().any(|dir| dir.to_str().is_some_and(|dir| indexer::DEFAULT_IGNORE_DIRS.contains(&dir))));
+	if ignored || !indexer::has_extension(path, &indexer::source_extensions()) {
+		return None;
+	}
+	Path::new(root).join(relative).to_str().map(str::to_owned)
+}
+
+// Drops the paths a gitignore-aware walk of `root` would not list, by running that same walk again, so nested and
+// freshly edited .gitignore files apply exactly as they do to indexing. Paths that no longer exist are kept, their
+// records still have to be removed.
+fn gitignore_filter(root: &str, paths: Vec<String>) -> std::io::Result<Vec<String>> {
+	let listed: HashSet<String> = indexer::list_files_gitignore_filtered(root, &indexer::source_extensions(), indexer::DEFAULT_IGNORE_DIRS)?.into_iter().collect();
+	Ok(paths.into_iter().filter(|path| listed.contains(path) || !Path::new(path).exists()).collect())
+}
+
+// Re-indexes one changed file into the code base. A file that no longer exists, or that is now too large or binary,
+// is dropped from it.
+fn reindex(codebase: &mut indexer::code_base, path: &str, provider: &dyn llm::LlmProvider, config: &llm::LlmConfig) -> Result<(), llm::LlmError> {
+	if !Path::new(path).is_file() {
+		codebase.remove_file(path);
+		return Ok(());
+	}
+	// Skipped the way indexing skips it, which is not a failure
+	if let Some(reason) = indexer::should_skip(path, config) {
+		log::warn!("Skipping {}: {}", path, reason);
+		codebase.remove_file(path);
+		return Ok(());
+	}
+	let (file, objects, morphisms) = indexer::index_file(path, provider, config)?;
+	let mut partial = indexer::code_base {
+		files: vec![file],
+		objects,
+		morphisms,
+		..Default::default()
+	};
+	partial.link_morphisms();
+	partial.retry_failed_embeddings(provider);
+	codebase.merge(partial);
+	Ok(())
+}
+
+// Watches `root` recursively and folds every change into `codebase` until the watcher stops. With `gitignore` the
+// files .gitignore rules exclude are left alone, as list_files_gitignore_filtered leaves them out of indexing.
+// `on_update` runs after each debounced batch with the paths that were re-indexed or removed.
+pub fn watch(
+	root: &str,
+	codebase: &mut indexer::code_base,
+	provider: &dyn llm::LlmProvider,
+	config: &llm::LlmConfig,
+	gitignore: bool,
+	mut on_update: impl FnMut(&indexer::code_base, &[String]),
+) -> notify::Result<()> {
+	use notify::Watcher;
+	let canonical_root = std::fs::canonicalize(root)?;
+	let (tx, rx) = std::sync::mpsc::channel();
+	let mut watcher = notify::recommended_watcher(tx)?;
+	watcher.watch(&canonical_root, notify::RecursiveMode::Recursive)?;
+	while let Some(paths) = next_batch(&rx, DEBOUNCE) {
+		let mut updated = Vec::new();
+		let mut changed: Vec<String> = paths.iter().filter_map(|path| indexed_path(root, &canonical_root, path)).collect();
+		if gitignore && !changed.is_empty() {
+			changed = gitignore_filter(root, changed).map_err(notify::Error::io)?;
+		}
+		for path in changed {
+			match reindex(codebase, &path, provider, config) {
+				Ok(()) => updated.push(path),
+				Err(err) => {
+					log::warn!("Failed to re-index {}: {}", path, err);
+					codebase.record_failure(&path, &err.to_string());
+				}
+			}
+		}
+		if !updated.is_empty() {
+			on_update(codebase, &updated);
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn rapid_events_collapse_into_one_batch() {
+		let (tx, rx) = std::sync::mpsc::channel();
+		let event = |path: &str| Ok(notify::Event::new(notify::EventKind::Any).add_path(PathBuf::from(path)));
+		tx.send(event("/repo/a.rs")).unwrap();
+		tx.send(event("/repo/a.rs")).unwrap();
+		tx.send(event("/repo/b.rs")).unwrap();
+		let batch = next_batch(&rx, Duration::from_millis(20)).unwrap();
+		assert_eq!(batch, vec![PathBuf::from("/repo/a.rs"), PathBuf::from("/repo/b.rs")]);
+		drop(tx);
+		assert!(next_batch(&rx, Duration::from_millis(20)).is_none());
+	}
+
+	#[test]
+	fn only_indexable_paths_under_the_root_are_kept() {
+		let root = Path::new("/repo");
+		assert_eq!(indexed_path("src", root, Path::new("/repo/lib/a.rs")), Some("src/lib/a.rs".to_string()));
+		assert_eq!(indexed_path("src", root, Path::new("/repo/target/debug/a.rs")), None);
+		assert_eq!(indexed_path("src", root, Path::new("/repo/notes.bin")), None);
+		assert_eq!(indexed_path("src", root, Path::new("/elsewhere/a.rs")), None);
+	}
+
+	#[test]
+	fn gitignored_changes_are_dropped_like_indexing_drops_them() {
+		let dir = std::env::temp_dir().join(format!("eve_watch_gitignore_test_{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("sub")).unwrap();
+		std::fs::write(dir.join(".gitignore"), "generated.rs\n").unwrap();
+		std::fs::write(dir.join("sub/.gitignore"), "*.rs\n!keep.rs\n").unwrap();
+		for file in ["main.rs", "generated.rs", "sub/drop.rs", "sub/keep.rs"] {
+			std::fs::write(dir.join(file), "").unwrap();
+		}
+		let root = dir.to_str().unwrap();
+		let changed = ["main.rs", "generated.rs", "sub/drop.rs", "sub/keep.rs", "deleted.rs"].iter().map(|file| dir.join(file).to_str().unwrap().to_string()).collect();
+		let kept = gitignore_filter(root, changed).unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		let expected: Vec<String> = ["main.rs", "sub/keep.rs", "deleted.rs"].iter().map(|file| dir.join(file).to_str().unwrap().to_string()).collect();
+		assert_eq!(kept, expected);
+	}
+
+	#[test]
+	fn files_that_turn_binary_are_skipped_not_failed() {
+		let dir = std::env::temp_dir().join(format!("eve_watch_skip_test_{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("blob.rs");
+		std::fs::write(&path, b"fn main() {}\0\0").unwrap();
+		let path = path.to_str().unwrap();
+		let mut codebase = indexer::code_base {
+			files: vec![llm::File { path: path.to_string(), ..Default::default() }],
+			..Default::default()
+		};
+		let provider = crate::mock::MockProvider::new();
+		let reindexed = reindex(&mut codebase, path, &provider, &llm::LlmConfig::default());
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert!(reindexed.is_ok());
+		assert!(codebase.files.is_empty());
+		assert!(codebase.failures.is_empty());
+	}
+}